@@ -1,4 +1,10 @@
-use cali_cli::scaffold::{controller::sync_protos_with_controllers, store::create_store};
+use std::path::{Path, PathBuf};
+
+use cali_cli::{
+    keys,
+    scaffold::{controller::sync_protos_with_controllers, store::create_store},
+};
+use cali_core::store::Backend;
 use clap::{Parser, Subcommand};
 
 /// Cali CLI
@@ -17,30 +23,81 @@ struct Cli {
 enum Commands {
     New {
         name: String,
+        /// The database backend, one of mysql, postgres or sqlite
+        #[arg(long, default_value = "mysql")]
+        db: Backend,
     },
     Generate {
         #[command(subcommand)]
         target: GenerateTarget,
     },
+    /// Manage the API keys in the cali_api_keys table
+    Keys {
+        #[command(subcommand)]
+        action: KeysAction,
+        /// The config file to read the database url from
+        #[arg(long, default_value = "./web/config/dev.yml")]
+        config: PathBuf,
+    },
 }
 
 #[derive(Subcommand, Debug)]
 enum GenerateTarget {
     Controllers,
-    Store { name: String },
+    Store {
+        name: String,
+    },
+    /// Migrations for the cali_api_keys table used by API key authentication
+    ApiKeys,
+}
+
+#[derive(Subcommand, Debug)]
+enum KeysAction {
+    /// Create a key, it is only printed once
+    Create {
+        name: String,
+        /// The subject the key authenticates as
+        #[arg(long)]
+        subject: String,
+        /// Comma separated roles granted to the key
+        #[arg(long, value_delimiter = ',')]
+        roles: Vec<String>,
+    },
+    /// List all keys, including revoked ones
+    List,
+    /// Revoke a key by its id
+    Revoke { id: i64 },
 }
 
 fn main() {
     let cli = Cli::parse();
 
-    if let Some(Commands::New { name }) = &cli.commands {
-        cali_cli::scaffold::new::create_app(name);
+    if let Some(Commands::New { name, db }) = &cli.commands {
+        cali_cli::scaffold::new::create_app(name, *db);
     }
 
     if let Some(Commands::Generate { target }) = &cli.commands {
         match target {
             GenerateTarget::Controllers => sync_protos_with_controllers(),
             GenerateTarget::Store { name } => create_store(name.clone()),
+            GenerateTarget::ApiKeys => {
+                let url = keys::database_url(Path::new("./web/config/dev.yml"));
+                let backend = Backend::from_url(&url)
+                    .unwrap_or_else(|| panic!("Can't tell the database backend of {}", url));
+                keys::create_migrations(Path::new("./store/migrations"), backend)
+            }
+        }
+    }
+
+    if let Some(Commands::Keys { action, config }) = &cli.commands {
+        match action {
+            KeysAction::Create {
+                name,
+                subject,
+                roles,
+            } => keys::create_key(config, name, subject, roles),
+            KeysAction::List => keys::list_keys(config),
+            KeysAction::Revoke { id } => keys::revoke_key(config, *id),
         }
     }
 }
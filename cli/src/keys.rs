@@ -0,0 +1,109 @@
+use std::{fs, path::Path};
+
+use cali_core::store::{
+    api_keys::{self, ApiKey},
+    Backend, DbConn,
+};
+use sqlx::Connection;
+
+/// Writes the migrations for the `cali_api_keys` table into the store's migrations directory.
+pub fn create_migrations(migrations_dir: &Path, backend: Backend) {
+    let files = [
+        (api_keys::up_migration(backend), "up"),
+        (api_keys::DOWN_MIGRATION, "down"),
+    ];
+
+    files.iter().for_each(|(content, direction)| {
+        let path = migrations_dir.join(format!(
+            "{}_cali_api_keys.{}.sql",
+            api_keys::MIGRATION_VERSION,
+            direction
+        ));
+        fs::write(path, content).expect("Could not write API key migration");
+    });
+}
+
+pub fn create_key(config: &Path, name: &str, subject: &str, roles: &[String]) {
+    with_connection(config, |mut conn| async move {
+        let (api_key, key) = api_keys::create(&mut conn, name, subject, roles)
+            .await
+            .expect("Could not create API key");
+        print_key(&api_key);
+        println!();
+        println!("{}", key);
+        println!("Store this key now, it can't be shown again.");
+    });
+}
+
+pub fn list_keys(config: &Path) {
+    with_connection(config, |mut conn| async move {
+        let keys = api_keys::list(&mut conn)
+            .await
+            .expect("Could not list API keys");
+        keys.iter().for_each(print_key);
+    });
+}
+
+pub fn revoke_key(config: &Path, id: i64) {
+    with_connection(config, |mut conn| async move {
+        let revoked = api_keys::revoke(&mut conn, id)
+            .await
+            .expect("Could not revoke API key");
+        if revoked {
+            println!("Revoked API key {}", id);
+        } else {
+            println!("No active API key with id {}", id);
+        }
+    });
+}
+
+fn print_key(api_key: &ApiKey) {
+    let status = match api_key.revoked_at {
+        Some(revoked_at) => format!("revoked {}", revoked_at.format("%Y-%m-%d %H:%M")),
+        None => "active".to_string(),
+    };
+    println!(
+        "{}\t{}\t{}...\t{}\t[{}]\t{}",
+        api_key.id, api_key.name, api_key.prefix, api_key.subject, api_key.roles, status
+    );
+}
+
+/// Reads `database.url` from the app's config file.
+pub fn database_url(config: &Path) -> String {
+    let config_file = fs::File::open(config)
+        .unwrap_or_else(|err| panic!("Could not open {}: {}", config.display(), err));
+    let config: serde_yaml::Value =
+        serde_yaml::from_reader(config_file).expect("Could not parse config file");
+
+    config["database"]["url"]
+        .as_str()
+        .expect("The config file has no database.url")
+        .to_string()
+}
+
+/// Connects to the database configured under `database.url` in the app's config file.
+fn with_connection<F, Fut>(config: &Path, run: F)
+where
+    F: FnOnce(DbConn) -> Fut,
+    Fut: std::future::Future<Output = ()>,
+{
+    let url = database_url(config);
+    if Backend::from_url(&url) != Some(Backend::CURRENT) {
+        panic!(
+            "This cali was installed for {}, reinstall it with `cargo install cali_cli --no-default-features --features <your database>` to manage keys in {}",
+            Backend::CURRENT,
+            url
+        );
+    }
+
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("Could not start the tokio runtime")
+        .block_on(async move {
+            let conn = DbConn::connect(&url)
+                .await
+                .expect("Could not connect to the database");
+            run(conn).await;
+        });
+}
@@ -58,6 +58,7 @@
 //! 2. Create some modules directly in the web crate to handle your logic.
 //! 3. Add a new cargo library and have web depend on it.
 //!
+pub mod keys;
 pub mod scaffold;
 
 pub static CORE_VERSION: &str = "0.3.0";
@@ -1,13 +1,15 @@
 use serde::Serialize;
 use std::{fs, path::Path};
 
+use cali_core::store::Backend;
 use tinytemplate::TinyTemplate;
 
-use crate::{CORE_VERSION, DERIVE_VERSION};
+use crate::{keys, CORE_VERSION, DERIVE_VERSION};
 
-pub fn create_app(name: &str) {
+pub fn create_app(name: &str, backend: Backend) {
     create_directories(name);
-    create_files(name);
+    create_files(name, backend);
+    keys::create_migrations(&Path::new(name).join("store/migrations"), backend);
 }
 
 fn create_directories(name: &str) {
@@ -17,6 +19,7 @@ fn create_directories(name: &str) {
         "interface/grpc",
         "interface/grpc/services",
         "interface/grpc/models",
+        "interface/grpc/cali",
         "web",
         "web/config",
         "web/src",
@@ -44,6 +47,18 @@ struct Context {
     name: String,
     core_version: &'static str,
     derive_version: &'static str,
+    /// The cargo feature of the database backend, for both cali_core and sqlx.
+    database: &'static str,
+    dev_database_url: String,
+    test_database_url: String,
+}
+
+fn database_url(name: &str, backend: Backend, env: &str) -> String {
+    match backend {
+        Backend::MySql => format!("mysql://root@127.0.0.1/{}_{}", name, env),
+        Backend::Postgres => format!("postgres://postgres@127.0.0.1/{}_{}", name, env),
+        Backend::Sqlite => format!("sqlite://{}_{}.db?mode=rwc", name, env),
+    }
 }
 
 // Template static strings
@@ -63,11 +78,13 @@ static STORE_CARGO_T: &'static str = include_str!("../../templates/store/Cargo.t
 static STORE_LIB_T: &'static str = include_str!("../../templates/store/src/lib.rs.tt");
 static STORE_REP_MOD_T: &'static str =
     include_str!("../../templates/store/src/repositories/mod.rs.tt");
+static CALI_OPTIONS_PROTO_T: &'static str =
+    include_str!("../../templates/interface/grpc/cali/options.proto.tt");
 static CARGO_WORKSPACE_T: &'static str = include_str!("../../templates/Cargo.toml.tt");
 static GITIGNORE_WORKSPACE_T: &'static str = include_str!("../../templates/.gitignore.tt");
 static README_WORKSPACE_T: &'static str = include_str!("../../templates/README.md.tt");
 
-fn create_files(name: &str) {
+fn create_files(name: &str, backend: Backend) {
     let files = [
         (WEB_MAINRS_T, format!("./{}/web/src/entry/main.rs", name)),
         (WEB_CARGO_T, format!("./{}/web/Cargo.toml", name)),
@@ -94,6 +111,10 @@ fn create_files(name: &str) {
             STORE_REP_MOD_T,
             format!("./{}/store/src/repositories/mod.rs", name),
         ),
+        (
+            CALI_OPTIONS_PROTO_T,
+            format!("./{}/interface/grpc/cali/options.proto", name),
+        ),
         (CARGO_WORKSPACE_T, format!("./{}/Cargo.toml", name)),
         (GITIGNORE_WORKSPACE_T, format!("./{}/.gitignore", name)),
         (README_WORKSPACE_T, format!("./{}/README.md", name)),
@@ -103,6 +124,9 @@ fn create_files(name: &str) {
         name: name.to_string(),
         core_version: CORE_VERSION,
         derive_version: DERIVE_VERSION,
+        database: backend.feature(),
+        dev_database_url: database_url(name, backend, "dev"),
+        test_database_url: database_url(name, backend, "test"),
     };
 
     files.iter().for_each(|(content, path)| {
@@ -55,19 +55,21 @@ pub fn create_store(name: String) {
     }
     .to_string();
 
+    let table = namespace.to_string();
+
     let store_model = quote! {
         use cali_derive::Ensnare;
         use sqlx::FromRow;
 
         #[derive(Clone, Debug, FromRow, Ensnare)]
+        #[snare(table = #table)]
         pub struct #model_ident {
+            #[snare(primary_key, auto_increment)]
             pub id: i64,
         }
     }
     .to_string();
 
-    let select = format!("SELECT * FROM {} WHERE id = ?", namespace);
-
     let store_implementation = quote! {
         use async_trait::async_trait;
         use cali_core::store::snare::{DBConnection, Ensnared};
@@ -78,12 +80,13 @@ pub fn create_store(name: String) {
         pub struct #repository {}
 
         #[async_trait]
-        impl #repository_contract<sqlx::MySql> for #repository {
+        impl #repository_contract<cali_core::store::Db> for #repository {
             async fn #get_function<'c, C: DBConnection<'c>, E: From<sqlx::Error>>(
                 conn: C,
                 id: i64,
             ) -> Result<Option<#model_ident>, E> {
-                let #namespace = sqlx::query_as::<_, #model_ident>(#select)
+                let #namespace = #model_ident::select()
+                    .filter("id = ?")
                     .bind(id)
                     .fetch_optional(conn)
                     .await?;
@@ -1,25 +1,44 @@
-use std::sync::Arc;
+use std::{any::TypeId, collections::HashMap, sync::Arc};
 
 use tonic::transport::Server;
 
+use crate::MapKey;
+
+pub mod server;
+
 pub struct CaliConfig<T, Stack, ResultStack> {
     pub global_context: Option<Arc<T>>,
+    /// Values added with `provide`, by type.
+    pub provided: HashMap<TypeId, MapKey>,
     pub database: bool,
     pub tokio_console: bool,
-    pub middleware_setup: Option<Box<dyn FnOnce(Server<Stack>) -> Server<ResultStack>>>,
+    pub catch_panics: bool,
+    /// Wraps the cali stack in your own middleware, leaves it as is unless `add_middleware` was
+    /// called.
+    pub middleware_setup: Box<dyn FnOnce(Server<Stack>) -> Server<ResultStack>>,
 }
 
-impl<T, Stack, ResultStack> CaliConfig<T, Stack, ResultStack> {
+impl<T, Stack> CaliConfig<T, Stack, Stack> {
     /// Creates a new CaliConfig
     pub fn new() -> Self {
         Self {
             tokio_console: false,
+            catch_panics: true,
             database: false,
             global_context: None,
-            middleware_setup: None,
+            provided: HashMap::new(),
+            middleware_setup: Box::new(|server| server),
         }
     }
+}
 
+impl<T, Stack> Default for CaliConfig<T, Stack, Stack> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, Stack, ResultStack> CaliConfig<T, Stack, ResultStack> {
     /// By default cali doesn't try to connect to your database, call this on the CaliConfig to
     /// enable the database connection functionality of Cali
     pub fn enable_database(mut self) -> Self {
@@ -28,24 +47,39 @@ impl<T, Stack, ResultStack> CaliConfig<T, Stack, ResultStack> {
         self
     }
 
-    /// Enables tokio console by calling `console_subscriber::init();`
+    /// Enables tokio console, installing `console_subscriber::spawn()` as a layer of the tracing
+    /// subscriber.
     pub fn enable_tokio_console(mut self) -> Self {
         self.tokio_console = true;
 
         self
     }
 
-    /// Takes a function that provides the Cali Tower Stack, and should return a new Tower Stack.
-    /// You can add all your Tower compliant middleware in here.
-    pub fn add_middleware(
-        mut self,
-        setup_fn: impl FnOnce(Server<Stack>) -> Server<ResultStack> + 'static,
-    ) -> Self {
-        self.middleware_setup = Some(Box::new(setup_fn));
+    /// By default a panic in a controller is answered with `Status::internal`, call this to let
+    /// panics unwind instead.
+    pub fn disable_panic_catching(mut self) -> Self {
+        self.catch_panics = false;
 
         self
     }
 
+    /// Takes a function that provides the Cali Tower Stack, and should return a new Tower Stack.
+    /// You can add all your Tower compliant middleware in here, e.g.
+    /// `.add_middleware(|server| server.layer(MyLayer))`. Replaces an earlier middleware function.
+    pub fn add_middleware<NewStack>(
+        self,
+        setup_fn: impl FnOnce(Server<Stack>) -> Server<NewStack> + 'static,
+    ) -> CaliConfig<T, Stack, NewStack> {
+        CaliConfig {
+            global_context: self.global_context,
+            provided: self.provided,
+            database: self.database,
+            tokio_console: self.tokio_console,
+            catch_panics: self.catch_panics,
+            middleware_setup: Box::new(setup_fn),
+        }
+    }
+
     // Allows the user of cali to make use of the global embedded context of cali. This is not to
     // be done without careful consideration of the consequences.
     pub fn add_global_context(mut self, global_context: T) -> Self {
@@ -53,4 +87,13 @@ impl<T, Stack, ResultStack> CaliConfig<T, Stack, ResultStack> {
 
         self
     }
+
+    /// Makes `value` available to every request through `get_context::<V>()`. Call it once per
+    /// type, e.g. for each client or cache your controllers share, a later value of the same type
+    /// replaces the earlier one.
+    pub fn provide<V: Send + Sync + 'static>(mut self, value: V) -> Self {
+        self.provided.insert(TypeId::of::<V>(), Arc::new(value));
+
+        self
+    }
 }
@@ -0,0 +1,76 @@
+use std::{collections::HashMap, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tonic::{codec::CompressionEncoding, transport::Server};
+
+/// Transport settings for the gRPC server, everything left out keeps tonic's default.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct ServerConf {
+    /// How many calls a single connection can have in flight, more calls wait their turn.
+    pub concurrency_limit_per_connection: Option<usize>,
+    /// Reject calls over `concurrency-limit-per-connection` with `Unavailable` instead of
+    /// queueing them. Connections without a remote address, like Unix sockets, aren't limited.
+    pub load_shed: bool,
+    pub http2_keepalive_interval_secs: Option<u64>,
+    pub http2_keepalive_timeout_secs: Option<u64>,
+    pub tcp_keepalive_secs: Option<u64>,
+    pub max_concurrent_streams: Option<u32>,
+    /// The largest message in bytes a service accepts, tonic defaults to 4MB.
+    pub max_decoding_message_size: Option<usize>,
+    /// The largest message in bytes a service sends, unlimited by default.
+    pub max_encoding_message_size: Option<usize>,
+    pub compression: CompressionConf,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct CompressionConf {
+    /// Used by services without their own entry.
+    pub default: Compression,
+    /// Keyed by service, e.g. `package.Service`.
+    pub services: HashMap<String, Compression>,
+}
+
+/// A service accepts requests compressed with its encoding, and compresses responses with it
+/// when the caller supports it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl ServerConf {
+    /// Applies the connection level settings to the server builder.
+    pub fn apply<L>(&self, server: Server<L>) -> Server<L> {
+        let mut server = server
+            .http2_keepalive_interval(self.http2_keepalive_interval_secs.map(Duration::from_secs))
+            .http2_keepalive_timeout(self.http2_keepalive_timeout_secs.map(Duration::from_secs))
+            .tcp_keepalive(self.tcp_keepalive_secs.map(Duration::from_secs))
+            .max_concurrent_streams(self.max_concurrent_streams);
+        // With load shedding the limit is enforced by the `LoadShedLayer` instead
+        if let (Some(limit), false) = (self.concurrency_limit_per_connection, self.load_shed) {
+            server = server.concurrency_limit_per_connection(limit);
+        }
+
+        server
+    }
+
+    /// The compression a service (`package.Service`) uses, if any.
+    pub fn compression_for(&self, service: &str) -> Option<CompressionEncoding> {
+        let compression = self
+            .compression
+            .services
+            .get(service)
+            .unwrap_or(&self.compression.default);
+
+        match compression {
+            Compression::None => None,
+            Compression::Gzip => Some(CompressionEncoding::Gzip),
+            Compression::Zstd => Some(CompressionEncoding::Zstd),
+        }
+    }
+}
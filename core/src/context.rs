@@ -0,0 +1,66 @@
+use std::{any::TypeId, collections::HashMap, sync::Arc};
+
+use crate::{helpers::ContextError, store::DbPool, MapKey, ServerContext, SERVER_CONTEXT};
+
+/// Everything registered on the server, by type: your config, `ServerContext`, the global context
+/// and the values added with `CaliConfig::provide`. Controllers are built from it at startup.
+#[derive(Debug, Clone)]
+pub struct GlobalContext {
+    values: Arc<HashMap<TypeId, MapKey>>,
+}
+
+impl GlobalContext {
+    pub fn new(values: Arc<HashMap<TypeId, MapKey>>) -> Self {
+        Self { values }
+    }
+
+    /// The context of the request currently being served, or of the `test_runner`.
+    pub fn current() -> Result<Self, ContextError> {
+        SERVER_CONTEXT
+            .try_with(|values| Self::new(values.clone()))
+            .map_err(|_| ContextError::OutsideRequest)
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self) -> Result<Arc<T>, ContextError> {
+        self.values
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.clone().downcast::<T>().ok())
+            .ok_or(ContextError::Missing(std::any::type_name::<T>()))
+    }
+
+    pub fn resolve<T: FromContext>(&self) -> Result<T, ContextError> {
+        T::from_context(self)
+    }
+}
+
+/// A dependency that can be built from the registered context. Implement it for your
+/// repositories and clients so controllers declared with `controller!` can hold them.
+///
+/// ```ignore
+/// impl FromContext for AccountRepo {
+///     fn from_context(context: &GlobalContext) -> Result<Self, ContextError> {
+///         Ok(Self { pool: context.resolve()? })
+///     }
+/// }
+/// ```
+pub trait FromContext: Sized {
+    fn from_context(context: &GlobalContext) -> Result<Self, ContextError>;
+}
+
+/// Any registered value, shared.
+impl<T: Send + Sync + 'static> FromContext for Arc<T> {
+    fn from_context(context: &GlobalContext) -> Result<Self, ContextError> {
+        context.get::<T>()
+    }
+}
+
+/// The main database pool, when the database is enabled.
+impl FromContext for DbPool {
+    fn from_context(context: &GlobalContext) -> Result<Self, ContextError> {
+        context
+            .get::<ServerContext>()?
+            .db_pool
+            .clone()
+            .ok_or(ContextError::Missing(std::any::type_name::<DbPool>()))
+    }
+}
@@ -1,6 +1,12 @@
-use std::str::FromStr;
+use std::{any::TypeId, fmt, str::FromStr, time::Duration};
 
-use crate::SERVER_CONTEXT;
+use tokio::time::Instant;
+use tonic::Status;
+
+use crate::{
+    middleware::{deadline::Deadline, request_id::RequestId},
+    REQUEST_CONTEXT, SERVER_CONTEXT,
+};
 
 pub fn split_host_and_port(addr: &str) -> (&str, u16) {
     let parts = addr.split(':').collect::<Vec<_>>();
@@ -14,13 +20,95 @@ pub fn split_host_and_port(addr: &str) -> (&str, u16) {
     }
 }
 
+/// Splits a gRPC request path like `/package.Service/Method` into its service and method parts.
+pub fn split_rpc_path(path: &str) -> (&str, &str) {
+    let path = path.trim_start_matches('/');
+    match path.split_once('/') {
+        Some((service, method)) => (service, method),
+        None => (path, ""),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContextError {
+    /// Called outside of a request, e.g. in a spawned task or a unit test.
+    OutsideRequest,
+    /// The context has no value of this type.
+    Missing(&'static str),
+}
+
+impl fmt::Display for ContextError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContextError::OutsideRequest => write!(f, "No cali context, not called in a request"),
+            ContextError::Missing(type_name) => write!(f, "No {} in the cali context", type_name),
+        }
+    }
+}
+
+impl std::error::Error for ContextError {}
+
+impl From<ContextError> for Status {
+    fn from(err: ContextError) -> Self {
+        tracing::error!("{}", err);
+        Status::internal("Internal server error")
+    }
+}
+
+/// Calls `thunk` with the value of type `T` in the cali context. Panics when there is none, see
+/// `try_get_context` to handle that instead.
 pub fn get_context<R, T: 'static>(thunk: impl FnOnce(&T) -> R) -> R {
-    SERVER_CONTEXT.with(|ctx| match ctx.get(&std::any::TypeId::of::<T>()) {
-        Some(svr_ctx) => thunk(
-            svr_ctx
-                .downcast_ref::<T>()
-                .expect("Guaranteed by HashMap structure"),
-        ),
-        None => panic!("Guaranteed by middleware"),
-    })
+    try_get_context(thunk).unwrap_or_else(|err| panic!("{}", err))
+}
+
+/// Calls `thunk` with the value of type `T` in the cali context, or tells you why there is none.
+pub fn try_get_context<R, T: 'static>(thunk: impl FnOnce(&T) -> R) -> Result<R, ContextError> {
+    SERVER_CONTEXT
+        .try_with(|ctx| {
+            ctx.get(&TypeId::of::<T>())
+                .and_then(|value| value.downcast_ref::<T>())
+                .map(thunk)
+                .ok_or(ContextError::Missing(std::any::type_name::<T>()))
+        })
+        .map_err(|_| ContextError::OutsideRequest)?
+}
+
+/// Returns the value of type `T` in the context of the request currently being served, if there
+/// is one. Unlike `get_context`, the values are set per request, by the middleware.
+pub fn get_request_context<T: Clone + 'static>() -> Option<T> {
+    REQUEST_CONTEXT
+        .try_with(|ctx| ctx.get::<T>())
+        .ok()
+        .flatten()
+}
+
+/// Stores `value` in the context of the request currently being served, for everything that runs
+/// after it to read with `get_request_context`. Replaces an earlier value of the same type.
+pub fn insert_request_context<T: Send + Sync + 'static>(value: T) -> Result<(), ContextError> {
+    REQUEST_CONTEXT
+        .try_with(|ctx| ctx.insert(value))
+        .map_err(|_| ContextError::OutsideRequest)
+}
+
+/// Returns the ID of the request currently being served, if there is one.
+pub fn get_request_id() -> Option<String> {
+    get_request_context::<RequestId>().map(|request_id| request_id.0)
+}
+
+/// Returns the deadline of the request currently being served, if it has one.
+pub fn get_deadline() -> Option<Instant> {
+    get_request_context::<Deadline>().map(|deadline| deadline.0)
+}
+
+/// Returns the time left before the current request's deadline, zero once it has passed.
+pub fn get_remaining_time() -> Option<Duration> {
+    get_deadline().map(|deadline| deadline.saturating_duration_since(Instant::now()))
+}
+
+/// Sets the `grpc-timeout` of an outgoing call to the time left on the current request, so
+/// downstream services stop working on it when the caller has given up.
+pub fn propagate_deadline<T>(request: &mut tonic::Request<T>) {
+    if let Some(remaining) = get_remaining_time() {
+        request.set_timeout(remaining);
+    }
 }
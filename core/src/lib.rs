@@ -1,23 +1,61 @@
+#[cfg(any(feature = "mysql", feature = "postgres", feature = "sqlite"))]
 use std::{
     any::{Any, TypeId},
     collections::HashMap,
-    sync::Arc,
+    sync::{atomic::AtomicUsize, Arc},
 };
 
+#[cfg(not(any(
+    feature = "mysql",
+    feature = "postgres",
+    feature = "sqlite",
+    feature = "proto-parser"
+)))]
+compile_error!("Enable one of the mysql, postgres or sqlite features of cali_core");
+
+#[cfg(any(
+    all(feature = "mysql", feature = "postgres"),
+    all(feature = "mysql", feature = "sqlite"),
+    all(feature = "postgres", feature = "sqlite"),
+))]
+compile_error!(
+    "Only one database backend can be enabled, use `default-features = false` to drop mysql"
+);
+
+#[cfg(any(feature = "mysql", feature = "postgres", feature = "sqlite"))]
 pub mod config;
+#[cfg(any(feature = "mysql", feature = "postgres", feature = "sqlite"))]
+pub mod context;
+#[cfg(any(feature = "mysql", feature = "postgres", feature = "sqlite"))]
 pub mod helpers;
+#[cfg(any(feature = "mysql", feature = "postgres", feature = "sqlite"))]
 pub mod logging;
+#[cfg(any(feature = "mysql", feature = "postgres", feature = "sqlite"))]
 pub mod middleware;
 pub mod protos;
+#[cfg(any(feature = "mysql", feature = "postgres", feature = "sqlite"))]
+pub mod server;
+#[cfg(any(feature = "mysql", feature = "postgres", feature = "sqlite"))]
 pub mod store;
+#[cfg(any(feature = "mysql", feature = "postgres", feature = "sqlite"))]
+pub mod task;
 
-#[derive(Debug, Clone)]
+#[cfg(any(feature = "mysql", feature = "postgres", feature = "sqlite"))]
+#[derive(Debug, Clone, Default)]
 pub struct ServerContext {
-    pub db_pool: Option<sqlx::MySqlPool>,
+    pub db_pool: Option<store::DbPool>,
+    /// Read replicas of `db_pool`, see `store::get_read_conn`.
+    pub read_pools: Vec<store::ReadReplica>,
+    pub next_read_pool: Arc<AtomicUsize>,
+    /// The pools of the `databases` config section, by name.
+    pub named_pools: HashMap<String, store::DbPool>,
 }
 
+#[cfg(any(feature = "mysql", feature = "postgres", feature = "sqlite"))]
 pub type MapKey = Arc<dyn Any + Send + Sync>;
 
+#[cfg(any(feature = "mysql", feature = "postgres", feature = "sqlite"))]
 tokio::task_local! {
     pub static SERVER_CONTEXT: Arc<HashMap<TypeId,MapKey>>;
+    pub static REQUEST_CONTEXT: middleware::request_context::RequestContext;
 }
@@ -1 +1,2 @@
+pub mod telemetry;
 pub mod util;
@@ -0,0 +1,232 @@
+use std::{
+    fmt::Write as _,
+    fs::OpenOptions,
+    io::Write,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use futures::future::BoxFuture;
+use opentelemetry::{trace::TracerProvider as _, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    export::trace::{ExportResult, SpanData, SpanExporter},
+    metrics::{PeriodicReader, SdkMeterProvider},
+    propagation::TraceContextPropagator,
+    runtime,
+    trace::TracerProvider,
+    Resource,
+};
+use serde::{Deserialize, Serialize};
+use tracing_subscriber::{
+    layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer, Registry,
+};
+
+use super::util::CaliFormat;
+
+/// A type erased layer that can be stacked onto cali's subscriber, e.g. the tokio console layer.
+pub type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct TracingConf {
+    /// Reported as `service.name` on exported spans, defaults to the application name.
+    pub service_name: Option<String>,
+    /// An `EnvFilter` directive, `RUST_LOG` takes precedence when it is set.
+    pub filter: String,
+    pub exporter: TraceExporter,
+}
+
+impl Default for TracingConf {
+    fn default() -> Self {
+        Self {
+            service_name: None,
+            filter: "info,tonic=info,sqlx=error".to_string(),
+            exporter: TraceExporter::None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", tag = "kind")]
+pub enum TraceExporter {
+    /// Spans are only used to enrich log lines.
+    #[default]
+    None,
+    /// Writes finished spans to stdout, one per line.
+    Stdout,
+    /// Appends finished spans to a file, one per line.
+    File { path: PathBuf },
+    /// Ships spans and metrics to an OpenTelemetry collector over gRPC. Without an endpoint the
+    /// exporter falls back to `OTEL_EXPORTER_OTLP_ENDPOINT` or `http://localhost:4317`.
+    Otlp { endpoint: Option<String> },
+}
+
+/// Keeps the tracer and meter providers alive, flushing any pending spans and metrics when
+/// dropped.
+pub struct TelemetryGuard {
+    provider: Option<TracerProvider>,
+    meter_provider: Option<SdkMeterProvider>,
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        if let Some(provider) = self.provider.take() {
+            if let Err(err) = provider.shutdown() {
+                eprintln!("Failed to flush spans on shutdown: {}", err);
+            }
+        }
+        if let Some(meter_provider) = self.meter_provider.take() {
+            if let Err(err) = meter_provider.shutdown() {
+                eprintln!("Failed to flush metrics on shutdown: {}", err);
+            }
+        }
+    }
+}
+
+/// Installs the global tracing subscriber. Log lines are always written to the console, spans are
+/// additionally exported according to `conf.exporter`. `log` records are bridged into tracing so
+/// existing `log::info!` calls keep working. W3C trace context is used to propagate spans across
+/// services.
+pub fn init(app_name: &str, conf: &TracingConf, extra_layer: Option<BoxedLayer>) -> TelemetryGuard {
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let service_name = conf
+        .service_name
+        .clone()
+        .unwrap_or_else(|| app_name.to_string());
+    let provider = build_provider(&service_name, &conf.exporter);
+    let meter_provider = build_meter_provider(&service_name, &conf.exporter);
+    if let Some(meter_provider) = &meter_provider {
+        opentelemetry::global::set_meter_provider(meter_provider.clone());
+    }
+
+    let otel_layer = provider.as_ref().map(|provider| {
+        tracing_opentelemetry::layer()
+            .with_tracer(provider.tracer(service_name.clone()))
+            .with_filter(filter(conf))
+    });
+
+    let fmt_layer = tracing_subscriber::fmt::layer()
+        .event_format(CaliFormat)
+        .with_filter(filter(conf));
+
+    let _ = tracing_subscriber::registry()
+        .with(extra_layer)
+        .with(otel_layer)
+        .with(fmt_layer)
+        .try_init();
+
+    TelemetryGuard {
+        provider,
+        meter_provider,
+    }
+}
+
+fn filter(conf: &TracingConf) -> EnvFilter {
+    EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&conf.filter))
+}
+
+fn build_provider(service_name: &str, exporter: &TraceExporter) -> Option<TracerProvider> {
+    let builder = TracerProvider::builder().with_resource(Resource::new(vec![KeyValue::new(
+        "service.name",
+        service_name.to_string(),
+    )]));
+
+    let builder = match exporter {
+        TraceExporter::None => return None,
+        TraceExporter::Stdout => {
+            builder.with_simple_exporter(LineExporter::new(Box::new(std::io::stdout())))
+        }
+        TraceExporter::File { path } => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .expect("Could not open the span export file");
+            builder.with_simple_exporter(LineExporter::new(Box::new(file)))
+        }
+        TraceExporter::Otlp { endpoint } => {
+            let mut exporter = opentelemetry_otlp::SpanExporter::builder().with_tonic();
+            if let Some(endpoint) = endpoint {
+                exporter = exporter.with_endpoint(endpoint);
+            }
+            let exporter = exporter.build().expect("Could not build the OTLP exporter");
+            builder.with_batch_exporter(exporter, runtime::Tokio)
+        }
+    };
+
+    Some(builder.build())
+}
+
+/// Metrics are only exported to a collector, the line based exporters are for spans.
+fn build_meter_provider(service_name: &str, exporter: &TraceExporter) -> Option<SdkMeterProvider> {
+    let TraceExporter::Otlp { endpoint } = exporter else {
+        return None;
+    };
+
+    let mut exporter = opentelemetry_otlp::MetricExporter::builder().with_tonic();
+    if let Some(endpoint) = endpoint {
+        exporter = exporter.with_endpoint(endpoint);
+    }
+    let exporter = exporter
+        .build()
+        .expect("Could not build the OTLP metric exporter");
+
+    Some(
+        SdkMeterProvider::builder()
+            .with_reader(PeriodicReader::builder(exporter, runtime::Tokio).build())
+            .with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                service_name.to_string(),
+            )]))
+            .build(),
+    )
+}
+
+/// Writes each finished span as a single human readable line, meant for local development.
+#[derive(Clone)]
+struct LineExporter {
+    writer: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl LineExporter {
+    fn new(writer: Box<dyn Write + Send>) -> Self {
+        Self {
+            writer: Arc::new(Mutex::new(writer)),
+        }
+    }
+}
+
+impl std::fmt::Debug for LineExporter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LineExporter").finish()
+    }
+}
+
+impl SpanExporter for LineExporter {
+    fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+        let mut writer = self.writer.lock().expect("Span writer poisoned");
+        for span in batch {
+            let elapsed = span
+                .end_time
+                .duration_since(span.start_time)
+                .unwrap_or_default();
+            let mut line = format!(
+                "trace_id={} span_id={} parent_id={} name={} elapsed={:?}",
+                span.span_context.trace_id(),
+                span.span_context.span_id(),
+                span.parent_span_id,
+                span.name,
+                elapsed
+            );
+            for attribute in span.attributes.iter() {
+                let _ = write!(line, " {}={}", attribute.key, attribute.value);
+            }
+            let _ = writeln!(writer, "{}", line);
+        }
+        let _ = writer.flush();
+
+        Box::pin(std::future::ready(Ok(())))
+    }
+}
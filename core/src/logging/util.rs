@@ -1,21 +1,56 @@
-use log::LevelFilter;
-use log4rs::append::console::ConsoleAppender;
-use log4rs::config::{Appender, Logger, Root};
-use log4rs::encode::pattern::PatternEncoder;
-use log4rs::Config;
+use std::fmt;
 
+use tracing::{Event, Subscriber};
+use tracing_log::NormalizeEvent;
+use tracing_subscriber::{
+    fmt::{
+        format::Writer,
+        time::{FormatTime, SystemTime},
+        FmtContext, FormatEvent, FormatFields,
+    },
+    registry::LookupSpan,
+};
+
+use super::telemetry::{self, TracingConf};
+use crate::helpers::get_request_id;
+
+/// Installs the console logger without exporting any spans. The server entrypoint calls
+/// `telemetry::init` with the loaded tracing config instead, this is mostly useful in tests.
 pub fn setup() {
-    let log_pattern = "{d(%Y-%m-%dT%H:%M:%S)} [{l}] {f}:{L} {m}{n}";
-    let stdout = ConsoleAppender::builder()
-        .encoder(Box::new(PatternEncoder::new(log_pattern)))
-        .build();
+    let _ = telemetry::init("cali", &TracingConf::default(), None);
+}
 
-    let config = Config::builder()
-        .appender(Appender::builder().build("stdout", Box::new(stdout)))
-        .logger(Logger::builder().build("tonic", LevelFilter::Info))
-        .logger(Logger::builder().build("sqlx", LevelFilter::Error))
-        .build(Root::builder().appender("stdout").build(LevelFilter::Info))
-        .unwrap();
+/// Cali's log line pattern: `{timestamp} [{level}] {file}:{line} [{request id}] {message}`. The
+/// request ID is left out for lines logged outside of a request.
+pub struct CaliFormat;
 
-    let _handle = log4rs::init_config(config).unwrap();
+impl<S, N> FormatEvent<S, N> for CaliFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        // Records bridged from the `log` crate carry their location in fields instead
+        let normalized = event.normalized_metadata();
+        let metadata = normalized.as_ref().unwrap_or_else(|| event.metadata());
+        SystemTime.format_time(&mut writer)?;
+        write!(
+            writer,
+            " [{}] {}:{}",
+            metadata.level(),
+            metadata.file().unwrap_or_else(|| metadata.target()),
+            metadata.line().unwrap_or(0)
+        )?;
+        if let Some(request_id) = get_request_id() {
+            write!(writer, " [{}]", request_id)?;
+        }
+        write!(writer, " ")?;
+        ctx.field_format().format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
 }
@@ -0,0 +1,315 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use bytes::Bytes;
+use http_body::{Body, Frame};
+use serde::{Deserialize, Serialize};
+use tonic::{body::BoxBody, transport::server::TcpConnectInfo};
+use tower::{Layer, Service};
+
+use super::request_context::RequestContext;
+use crate::{protos::wire::Schema, REQUEST_CONTEXT};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct AccessLogConf {
+    pub enabled: bool,
+    /// Field names, as `field` or `Message.field`, to redact on top of the ones marked with
+    /// `[(cali.sensitive) = true]` in the protos.
+    pub redact: Vec<String>,
+    /// How much of each body is kept for logging at debug level.
+    pub max_body_bytes: usize,
+}
+
+impl Default for AccessLogConf {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            redact: Vec::new(),
+            max_body_bytes: 4096,
+        }
+    }
+}
+
+/// Logs method, peer, status, latency and payload sizes of every RPC once its response has been
+/// sent. With `cali_core::middleware::access_log=debug` in the tracing filter the decoded request
+/// and response messages are logged as well, with sensitive fields redacted.
+#[derive(Debug, Clone, Default)]
+pub struct AccessLogLayer {
+    inner: Option<Arc<Inner>>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    conf: AccessLogConf,
+    schema: Schema,
+}
+
+impl AccessLogLayer {
+    pub fn new(conf: Option<&AccessLogConf>, schema: Schema) -> Self {
+        let conf = conf.cloned().unwrap_or_default();
+        if !conf.enabled {
+            return Self::default();
+        }
+
+        Self {
+            inner: Some(Arc::new(Inner { conf, schema })),
+        }
+    }
+}
+
+impl<S> Layer<S> for AccessLogLayer {
+    type Service = AccessLogService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        AccessLogService {
+            service,
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AccessLogService<S> {
+    service: S,
+    inner: Option<Arc<Inner>>,
+}
+
+impl<S, ResBody> Service<http::Request<BoxBody>> for AccessLogService<S>
+where
+    S: Service<http::Request<BoxBody>, Response = http::Response<ResBody>>,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+    ResBody: Body<Data = Bytes> + Send + 'static,
+    ResBody::Error: Into<tonic::codegen::StdError>,
+{
+    type Response = http::Response<BoxBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<BoxBody>) -> Self::Future {
+        let Some(inner) = self.inner.clone() else {
+            let future = self.service.call(request);
+            return Box::pin(async move {
+                future
+                    .await
+                    .map(|response| response.map(tonic::body::boxed))
+            });
+        };
+
+        let capture_limit = if tracing::enabled!(tracing::Level::DEBUG) {
+            inner.conf.max_body_bytes
+        } else {
+            0
+        };
+        let record = Arc::new(Record {
+            inner,
+            path: request.uri().path().to_string(),
+            peer: request
+                .extensions()
+                .get::<TcpConnectInfo>()
+                .and_then(|info| info.remote_addr())
+                .map(|addr| addr.to_string())
+                .unwrap_or_default(),
+            context: REQUEST_CONTEXT.try_with(|ctx| ctx.clone()).ok(),
+            started_at: Instant::now(),
+            request: Mutex::new(Capture::new(capture_limit)),
+            response: Mutex::new(Capture::new(capture_limit)),
+            status: Mutex::new(None),
+        });
+
+        let request = request.map(|body| {
+            tonic::body::boxed(CaptureBody {
+                inner: Box::pin(body),
+                record: record.clone(),
+                side: Side::Request,
+            })
+        });
+        let future = self.service.call(request);
+
+        Box::pin(async move {
+            let response = future.await?;
+            if let Some(status) = grpc_status(response.headers()) {
+                *record.status.lock().unwrap() = Some(status);
+            }
+
+            Ok(response.map(|body| {
+                tonic::body::boxed(CaptureBody {
+                    inner: Box::pin(body),
+                    record,
+                    side: Side::Response,
+                })
+            }))
+        })
+    }
+}
+
+fn grpc_status(headers: &http::HeaderMap) -> Option<i32> {
+    headers
+        .get("grpc-status")
+        .and_then(|status| status.to_str().ok())
+        .and_then(|status| status.parse().ok())
+}
+
+struct Capture {
+    size: usize,
+    limit: usize,
+    bytes: Vec<u8>,
+}
+
+impl Capture {
+    fn new(limit: usize) -> Self {
+        Self {
+            size: 0,
+            limit,
+            bytes: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        self.size += data.len();
+        let room = self.limit.saturating_sub(self.bytes.len());
+        self.bytes.extend_from_slice(&data[..room.min(data.len())]);
+    }
+
+    /// Renders each length prefixed gRPC message in the captured bytes.
+    fn render(&self, schema: &Schema, message: Option<&str>, redact: &[String]) -> String {
+        let mut rendered = Vec::new();
+        let mut bytes = self.bytes.as_slice();
+        while bytes.len() >= 5 {
+            let compressed = bytes[0] == 1;
+            let length = u32::from_be_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]) as usize;
+            if bytes.len() < 5 + length {
+                break;
+            }
+            let (frame, rest) = bytes[5..].split_at(length);
+            bytes = rest;
+            rendered.push(match message {
+                _ if compressed => format!("<{} compressed bytes>", length),
+                Some(message) => schema.render(message, frame, redact),
+                None => format!("<{} bytes>", length),
+            });
+        }
+        if self.size > self.bytes.len() {
+            rendered.push("<truncated>".to_string());
+        }
+
+        rendered.join(" ")
+    }
+}
+
+struct Record {
+    inner: Arc<Inner>,
+    path: String,
+    peer: String,
+    context: Option<RequestContext>,
+    started_at: Instant,
+    request: Mutex<Capture>,
+    response: Mutex<Capture>,
+    status: Mutex<Option<i32>>,
+}
+
+impl Record {
+    fn log(&self) {
+        let request = self.request.lock().unwrap();
+        let response = self.response.lock().unwrap();
+        let status = self
+            .status
+            .lock()
+            .unwrap()
+            .map(|status| status.to_string())
+            .unwrap_or_else(|| "-".to_string());
+
+        tracing::info!(
+            peer = self.peer,
+            grpc.status = status,
+            latency_ms = self.started_at.elapsed().as_millis() as u64,
+            request_bytes = request.size,
+            response_bytes = response.size,
+            "{}",
+            self.path
+        );
+
+        if request.limit > 0 {
+            let schema = &self.inner.schema;
+            let redact = &self.inner.conf.redact;
+            let messages = schema.rpc_messages(self.path.trim_start_matches('/'));
+            tracing::debug!(
+                "{} request {}",
+                self.path,
+                request.render(schema, messages.map(|(message, _)| message), redact)
+            );
+            tracing::debug!(
+                "{} response {}",
+                self.path,
+                response.render(schema, messages.map(|(_, message)| message), redact)
+            );
+        }
+    }
+}
+
+impl Drop for Record {
+    /// The request and response bodies share the record, so it's dropped once the whole call has
+    /// been sent. The log line is written outside of the request's task, so its context is
+    /// restored.
+    fn drop(&mut self) {
+        match self.context.clone() {
+            Some(context) => REQUEST_CONTEXT.sync_scope(context, || self.log()),
+            None => self.log(),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Side {
+    Request,
+    Response,
+}
+
+struct CaptureBody<B> {
+    inner: Pin<Box<B>>,
+    record: Arc<Record>,
+    side: Side,
+}
+
+impl<B: Body<Data = Bytes>> Body for CaptureBody<B> {
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let frame = self.inner.as_mut().poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &frame {
+            if let Some(data) = frame.data_ref() {
+                let capture = match self.side {
+                    Side::Request => &self.record.request,
+                    Side::Response => &self.record.response,
+                };
+                capture.lock().unwrap().push(data);
+            } else if let Some(status) = frame.trailers_ref().and_then(grpc_status) {
+                *self.record.status.lock().unwrap() = Some(status);
+            }
+        }
+
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        self.inner.size_hint()
+    }
+}
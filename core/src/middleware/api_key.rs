@@ -0,0 +1,281 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use http::HeaderName;
+use serde::{Deserialize, Serialize};
+use tonic::Status;
+use tower::{Layer, Service};
+
+use super::{authorization::Principal, status_response};
+use crate::{
+    helpers::insert_request_context,
+    store::{api_keys, try_get_conn, ConnError},
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct ApiKeyConf {
+    /// The metadata key callers send their API key in.
+    pub header: String,
+    /// How long a valid key is remembered, revoked keys keep working for at most this long.
+    pub cache_ttl_secs: u64,
+}
+
+impl Default for ApiKeyConf {
+    fn default() -> Self {
+        Self {
+            header: "x-api-key".to_string(),
+            cache_ttl_secs: 60,
+        }
+    }
+}
+
+type KeyCache = Mutex<HashMap<String, (Principal, Instant)>>;
+
+/// Left in the request context when the caller sent an unknown or revoked key, so the `AuthLayer`
+/// can reject the call with the right message, or let it through on anonymous methods.
+#[derive(Debug, Clone)]
+pub(crate) struct RejectedApiKey;
+
+struct Resolver {
+    header: HeaderName,
+    ttl: Duration,
+    cache: KeyCache,
+}
+
+impl Resolver {
+    async fn resolve(&self, key: &str) -> Result<Option<Principal>, ConnError> {
+        let hash = api_keys::hash_key(key);
+        if let Some((principal, cached_at)) = self.cache.lock().unwrap().get(&hash) {
+            if cached_at.elapsed() < self.ttl {
+                return Ok(Some(principal.clone()));
+            }
+        }
+
+        let mut conn = try_get_conn().await?;
+        let principal = api_keys::find_active(&mut *conn, key)
+            .await?
+            .map(|api_key| Principal {
+                roles: api_key.role_list(),
+                subject: api_key.subject,
+            });
+
+        // Misses aren't cached, callers sending random keys would grow the cache without bound
+        if let Some(principal) = &principal {
+            let mut cache = self.cache.lock().unwrap();
+            cache.retain(|_, (_, cached_at)| cached_at.elapsed() < self.ttl);
+            cache.insert(hash, (principal.clone(), Instant::now()));
+        }
+
+        Ok(principal)
+    }
+}
+
+/// Resolves the API key sent in the `x-api-key` metadata to a principal using the
+/// `cali_api_keys` table. Calls without a valid key are left to the `AuthLayer`, which rejects
+/// unknown or revoked keys with `Unauthenticated` unless the method allows anonymous access. Needs
+/// the database to be enabled.
+#[derive(Clone, Default)]
+pub struct ApiKeyLayer {
+    resolver: Option<Arc<Resolver>>,
+}
+
+impl ApiKeyLayer {
+    pub fn new(conf: Option<&ApiKeyConf>) -> Result<Self, String> {
+        let resolver = match conf {
+            Some(conf) => Some(Arc::new(Resolver {
+                header: HeaderName::from_lowercase(conf.header.to_lowercase().as_bytes())
+                    .map_err(|err| format!("Invalid API key header: {}", err))?,
+                ttl: Duration::from_secs(conf.cache_ttl_secs),
+                cache: Mutex::new(HashMap::new()),
+            })),
+            None => None,
+        };
+
+        Ok(Self { resolver })
+    }
+}
+
+impl<S> Layer<S> for ApiKeyLayer {
+    type Service = ApiKeyService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        ApiKeyService {
+            service,
+            resolver: self.resolver.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ApiKeyService<S> {
+    service: S,
+    resolver: Option<Arc<Resolver>>,
+}
+
+impl<S, ReqBody, ResBody> Service<http::Request<ReqBody>> for ApiKeyService<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+    ReqBody: Send + 'static,
+    ResBody: Default + Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<ReqBody>) -> Self::Future {
+        let Some(resolver) = self.resolver.clone() else {
+            return Box::pin(self.service.call(request));
+        };
+        let Some(key) = request
+            .headers()
+            .get(&resolver.header)
+            .and_then(|key| key.to_str().ok())
+            .map(|key| key.to_string())
+        else {
+            return Box::pin(self.service.call(request));
+        };
+
+        // The lookup is async, so the service that was polled ready is the one we call later
+        let clone = self.service.clone();
+        let mut service = std::mem::replace(&mut self.service, clone);
+
+        Box::pin(async move {
+            let inserted = match resolver.resolve(&key).await {
+                Ok(Some(principal)) => insert_request_context(principal),
+                Ok(None) => insert_request_context(RejectedApiKey),
+                Err(err) => return Ok(status_response(Status::from(err))),
+            };
+            match inserted {
+                Ok(()) => service.call(request).await,
+                Err(err) => Ok(status_response(Status::from(err))),
+            }
+        })
+    }
+}
+
+// The keys live in an in-memory SQLite database, so these only run with the sqlite feature
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use std::convert::Infallible;
+
+    use tonic::Code;
+
+    use super::*;
+    use crate::{
+        helpers::get_request_context,
+        middleware::auth::{AuthConf, AuthLayer},
+        store::{with_memory_db, Backend, DbPool},
+    };
+
+    async fn create_key(pool: &DbPool) -> (api_keys::ApiKey, String) {
+        let mut conn = pool.acquire().await.unwrap();
+        sqlx::query(api_keys::up_migration(Backend::Sqlite))
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        let roles = ["reader".to_string(), "writer".to_string()];
+        api_keys::create(&mut conn, "reports", "svc-reports", &roles)
+            .await
+            .unwrap()
+    }
+
+    fn api_key_layer(ttl: Duration) -> ApiKeyLayer {
+        ApiKeyLayer {
+            resolver: Some(Arc::new(Resolver {
+                header: HeaderName::from_static("x-api-key"),
+                ttl,
+                cache: Mutex::new(HashMap::new()),
+            })),
+        }
+    }
+
+    /// Calls a protected method with `key` through the API key and auth layers, returning the
+    /// status it was answered with, if any, and the caller the method saw.
+    async fn call(layer: &ApiKeyLayer, key: &str) -> (Option<Code>, Option<Principal>) {
+        let auth = AuthLayer::new(Some(&AuthConf {
+            api_keys: Some(ApiKeyConf::default()),
+            ..Default::default()
+        }))
+        .unwrap();
+        let mut service =
+            layer.layer(auth.layer(tower::service_fn(|_: http::Request<()>| async {
+                Ok::<_, Infallible>(http::Response::new(get_request_context::<Principal>()))
+            })));
+        let request = http::Request::builder()
+            .uri("/pkg.Private/Get")
+            .header("x-api-key", key)
+            .body(())
+            .unwrap();
+
+        // Every call gets a request context of its own
+        let response = crate::REQUEST_CONTEXT
+            .scope(Default::default(), service.call(request))
+            .await
+            .unwrap();
+        let code = Status::from_header_map(response.headers()).map(|status| status.code());
+        (code, response.into_body())
+    }
+
+    #[tokio::test]
+    async fn puts_the_principal_of_a_valid_key_in_the_context() {
+        with_memory_db(|pool| async move {
+            let (_, key) = create_key(&pool).await;
+
+            let (code, principal) = call(&api_key_layer(Duration::from_secs(60)), &key).await;
+            let principal = principal.unwrap();
+
+            assert_eq!(code, None);
+            assert_eq!(principal.subject, "svc-reports");
+            assert_eq!(principal.roles, vec!["reader", "writer"]);
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn rejects_an_unknown_key() {
+        with_memory_db(|pool| async move {
+            create_key(&pool).await;
+
+            let (code, principal) =
+                call(&api_key_layer(Duration::from_secs(60)), "ck_unknown").await;
+
+            assert_eq!(code, Some(Code::Unauthenticated));
+            assert!(principal.is_none());
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn rejects_a_revoked_key_once_it_leaves_the_cache() {
+        with_memory_db(|pool| async move {
+            let (api_key, key) = create_key(&pool).await;
+            let layer = api_key_layer(Duration::from_millis(100));
+
+            assert_eq!(call(&layer, &key).await.0, None);
+            assert!(api_keys::revoke(&pool, api_key.id).await.unwrap());
+
+            // Still cached, see `cache_ttl_secs`
+            assert_eq!(call(&layer, &key).await.0, None);
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            assert_eq!(call(&layer, &key).await.0, Some(Code::Unauthenticated));
+
+            // A revoked key that was never cached is rejected right away
+            let fresh = api_key_layer(Duration::from_secs(60));
+            assert_eq!(call(&fresh, &key).await.0, Some(Code::Unauthenticated));
+        })
+        .await
+    }
+}
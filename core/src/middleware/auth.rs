@@ -0,0 +1,555 @@
+use std::{
+    future::Future,
+    path::PathBuf,
+    pin::Pin,
+    str::FromStr,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use http::HeaderMap;
+use jsonwebtoken::{
+    jwk::{AlgorithmParameters, Jwk, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tonic::Status;
+use tower::{Layer, Service};
+
+use super::{
+    api_key::{ApiKeyConf, RejectedApiKey},
+    authorization::Principal,
+    status_response,
+};
+use crate::helpers::{get_request_context, insert_request_context, split_rpc_path};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct AuthConf {
+    /// A PEM encoded public key that every token has to be signed with.
+    pub pem: Option<PathBuf>,
+    /// The algorithm tokens are signed with when using `pem`, defaults to RS256.
+    pub algorithm: Option<Algorithm>,
+    /// A local JWKS document, the key is picked using the `kid` in the token header.
+    pub jwks: Option<PathBuf>,
+    /// Accepted `iss` claims, left unchecked when empty.
+    pub issuer: Vec<String>,
+    /// Accepted `aud` claims, left unchecked when empty.
+    pub audience: Vec<String>,
+    /// Clock skew in seconds allowed when checking `exp` and `nbf`.
+    pub leeway: u64,
+    /// The claim holding the caller's roles, either a list or a space separated string.
+    pub roles_claim: String,
+    /// Services (`package.Service`) or methods (`package.Service/Method`) that can be called
+    /// without a token.
+    pub allow_anonymous: Vec<String>,
+    /// Also accept API keys from the `cali_api_keys` table, alongside or instead of JWTs.
+    pub api_keys: Option<ApiKeyConf>,
+}
+
+impl Default for AuthConf {
+    fn default() -> Self {
+        Self {
+            pem: None,
+            algorithm: None,
+            jwks: None,
+            issuer: Vec::new(),
+            audience: Vec::new(),
+            leeway: 60,
+            roles_claim: "roles".to_string(),
+            allow_anonymous: Vec::new(),
+            api_keys: None,
+        }
+    }
+}
+
+/// The decoded claims of a verified bearer token.
+#[derive(Debug, Clone)]
+pub struct Claims(Arc<serde_json::Value>);
+
+impl Claims {
+    pub fn subject(&self) -> Option<&str> {
+        self.0.get("sub").and_then(|subject| subject.as_str())
+    }
+
+    pub fn value(&self) -> &serde_json::Value {
+        &self.0
+    }
+
+    /// Deserializes the claims into your own claims type.
+    pub fn deserialize<T: DeserializeOwned>(&self) -> Result<T, serde_json::Error> {
+        T::deserialize(self.0.as_ref())
+    }
+}
+
+/// Returns the claims of the caller's token in your own claims type. `None` when the method
+/// allows anonymous access and no valid token was sent, or when the claims don't deserialize.
+pub fn get_claims<T: DeserializeOwned>() -> Option<T> {
+    get_request_context::<Claims>().and_then(|claims| claims.deserialize().ok())
+}
+
+enum Keys {
+    Static(DecodingKey, Algorithm),
+    Set(JwkSet),
+}
+
+struct Verifier {
+    keys: Keys,
+    conf: AuthConf,
+}
+
+impl Verifier {
+    fn from_conf(conf: &AuthConf) -> Result<Option<Self>, String> {
+        let keys = match (&conf.pem, &conf.jwks) {
+            (Some(pem), None) => {
+                let algorithm = conf.algorithm.unwrap_or(Algorithm::RS256);
+                let pem = std::fs::read(pem)
+                    .map_err(|err| format!("Could not read {}: {}", pem.display(), err))?;
+                let key = match algorithm {
+                    Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(&pem),
+                    Algorithm::EdDSA => DecodingKey::from_ed_pem(&pem),
+                    Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                        return Err("HMAC algorithms can't be used with a PEM key".to_string())
+                    }
+                    _ => DecodingKey::from_rsa_pem(&pem),
+                }
+                .map_err(|err| format!("Invalid PEM key: {}", err))?;
+                Keys::Static(key, algorithm)
+            }
+            (None, Some(jwks)) => {
+                let jwks = std::fs::read_to_string(jwks)
+                    .map_err(|err| format!("Could not read {}: {}", jwks.display(), err))?;
+                let set = serde_json::from_str::<JwkSet>(&jwks)
+                    .map_err(|err| format!("Invalid JWKS document: {}", err))?;
+                Keys::Set(set)
+            }
+            (None, None) if conf.api_keys.is_some() => return Ok(None),
+            _ => return Err("Auth needs exactly one of `pem` or `jwks`, or `api-keys`".to_string()),
+        };
+
+        Ok(Some(Self {
+            keys,
+            conf: conf.clone(),
+        }))
+    }
+
+    fn verify(&self, token: &str) -> Result<Claims, String> {
+        let (key, algorithms) = match &self.keys {
+            Keys::Static(key, algorithm) => (key.clone(), vec![*algorithm]),
+            Keys::Set(set) => {
+                let header = jsonwebtoken::decode_header(token)
+                    .map_err(|err| format!("Invalid token: {}", err))?;
+                let jwk = match &header.kid {
+                    Some(kid) => set.find(kid),
+                    None if set.keys.len() == 1 => set.keys.first(),
+                    None => None,
+                }
+                .ok_or_else(|| "No key found for the token".to_string())?;
+                let key =
+                    DecodingKey::from_jwk(jwk).map_err(|err| format!("Unusable key: {}", err))?;
+                (key, jwk_algorithms(jwk))
+            }
+        };
+
+        let mut validation = Validation::new(algorithms[0]);
+        validation.algorithms = algorithms;
+        validation.leeway = self.conf.leeway;
+        if self.conf.audience.is_empty() {
+            validation.validate_aud = false;
+        } else {
+            validation.set_audience(&self.conf.audience);
+        }
+        if !self.conf.issuer.is_empty() {
+            validation.set_issuer(&self.conf.issuer);
+        }
+
+        jsonwebtoken::decode::<serde_json::Value>(token, &key, &validation)
+            .map(|data| Claims(Arc::new(data.claims)))
+            .map_err(|err| format!("Invalid token: {}", err))
+    }
+
+    fn principal(&self, claims: &Claims) -> Principal {
+        let roles = match claims.value().get(&self.conf.roles_claim) {
+            Some(serde_json::Value::Array(roles)) => roles
+                .iter()
+                .filter_map(|role| role.as_str())
+                .map(|role| role.to_string())
+                .collect(),
+            Some(serde_json::Value::String(roles)) => roles
+                .split_whitespace()
+                .map(|role| role.to_string())
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        Principal {
+            subject: claims.subject().unwrap_or_default().to_string(),
+            roles,
+        }
+    }
+}
+
+/// The algorithms a JWK may be used with. Pinned to the key's `alg` when it declares one,
+/// otherwise every algorithm of the key's family, so a token can't pick an unrelated one.
+fn jwk_algorithms(jwk: &Jwk) -> Vec<Algorithm> {
+    if let Some(algorithm) = jwk
+        .common
+        .key_algorithm
+        .and_then(|algorithm| Algorithm::from_str(&algorithm.to_string()).ok())
+    {
+        return vec![algorithm];
+    }
+
+    match jwk.algorithm {
+        AlgorithmParameters::RSA(_) => vec![
+            Algorithm::RS256,
+            Algorithm::RS384,
+            Algorithm::RS512,
+            Algorithm::PS256,
+            Algorithm::PS384,
+            Algorithm::PS512,
+        ],
+        AlgorithmParameters::EllipticCurve(_) => vec![Algorithm::ES256, Algorithm::ES384],
+        AlgorithmParameters::OctetKeyPair(_) => vec![Algorithm::EdDSA],
+        AlgorithmParameters::OctetKey(_) => {
+            vec![Algorithm::HS256, Algorithm::HS384, Algorithm::HS512]
+        }
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| {
+            value
+                .strip_prefix("Bearer ")
+                .or_else(|| value.strip_prefix("bearer "))
+        })
+        .map(|token| token.trim())
+}
+
+/// Validates the bearer token in the `authorization` metadata and puts its `Claims` and the
+/// `Principal` in the request context, available through `get_claims` and `get_principal`. Calls
+/// already authenticated by the `ApiKeyLayer` pass through. Calls without valid credentials are
+/// rejected with `Unauthenticated`, unless the method allows anonymous access. Without an auth
+/// config every call passes.
+#[derive(Clone, Default)]
+pub struct AuthLayer {
+    enabled: bool,
+    verifier: Option<Arc<Verifier>>,
+    anonymous: Arc<Vec<String>>,
+}
+
+impl AuthLayer {
+    pub fn new(conf: Option<&AuthConf>) -> Result<Self, String> {
+        let verifier = match conf {
+            Some(conf) => Verifier::from_conf(conf)?.map(Arc::new),
+            None => None,
+        };
+        let anonymous = conf
+            .map(|conf| conf.allow_anonymous.clone())
+            .unwrap_or_default();
+
+        Ok(Self {
+            enabled: conf.is_some(),
+            verifier,
+            anonymous: Arc::new(anonymous),
+        })
+    }
+
+    /// Allows these services (`package.Service`) or methods (`package.Service/Method`) to be
+    /// called without a token, on top of the ones listed in the config.
+    pub fn allow_anonymous(mut self, methods: &[&str]) -> Self {
+        Arc::make_mut(&mut self.anonymous).extend(methods.iter().map(|method| method.to_string()));
+
+        self
+    }
+}
+
+fn allows_anonymous(anonymous: &[String], service: &str, method: &str) -> bool {
+    anonymous.iter().any(|entry| match entry.split_once('/') {
+        Some((allowed_service, allowed_method)) => {
+            allowed_service == service && allowed_method == method
+        }
+        None => entry == service,
+    })
+}
+
+impl<S> Layer<S> for AuthLayer {
+    type Service = AuthService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        AuthService {
+            service,
+            enabled: self.enabled,
+            verifier: self.verifier.clone(),
+            anonymous: self.anonymous.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AuthService<S> {
+    service: S,
+    enabled: bool,
+    verifier: Option<Arc<Verifier>>,
+    anonymous: Arc<Vec<String>>,
+}
+
+impl<S, ReqBody, ResBody> Service<http::Request<ReqBody>> for AuthService<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<ResBody>>,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+    ResBody: Default + Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<ReqBody>) -> Self::Future {
+        if !self.enabled || get_request_context::<Principal>().is_some() {
+            return Box::pin(self.service.call(request));
+        }
+
+        let (service, method) = split_rpc_path(request.uri().path());
+        let anonymous = allows_anonymous(&self.anonymous, service, method);
+        let token = bearer_token(request.headers());
+
+        let rejection = match (&self.verifier, token) {
+            (Some(verifier), Some(token)) => match verifier.verify(token) {
+                Ok(claims) => {
+                    let principal = verifier.principal(&claims);
+                    if let Err(err) = insert_request_context(principal)
+                        .and_then(|_| insert_request_context(claims))
+                    {
+                        let status = Status::from(err);
+                        return Box::pin(std::future::ready(Ok(status_response(status))));
+                    }
+                    None
+                }
+                Err(message) => Some(message),
+            },
+            _ if get_request_context::<RejectedApiKey>().is_some() => {
+                Some("Invalid or revoked API key".to_string())
+            }
+            _ => Some("Missing credentials".to_string()),
+        };
+
+        match rejection {
+            Some(message) if !anonymous => {
+                let status = Status::unauthenticated(message);
+                Box::pin(std::future::ready(Ok(status_response(status))))
+            }
+            _ => Box::pin(self.service.call(request)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+    use tonic::Code;
+
+    use super::*;
+    use crate::{middleware::request_context::RequestContext, REQUEST_CONTEXT};
+
+    const SECRET: &[u8] = b"cali-test-secret-with-32-bytes!!";
+    const RSA_KEY: &[u8] = include_bytes!("testdata/rs256.key.pem");
+    const RSA_PUBLIC_KEY: &[u8] = include_bytes!("testdata/rs256.pub.pem");
+
+    fn testdata(file: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("src/middleware/testdata")
+            .join(file)
+    }
+
+    fn hs256_conf() -> AuthConf {
+        AuthConf {
+            jwks: Some(testdata("hs256.jwks.json")),
+            ..Default::default()
+        }
+    }
+
+    fn rs256_conf() -> AuthConf {
+        AuthConf {
+            pem: Some(testdata("rs256.pub.pem")),
+            ..Default::default()
+        }
+    }
+
+    fn token_claims(extra: serde_json::Value) -> serde_json::Value {
+        let mut claims = json!({
+            "sub": "user-1",
+            "roles": ["admin"],
+            "exp": jsonwebtoken::get_current_timestamp() + 3600,
+        });
+        claims
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        claims
+    }
+
+    fn hmac_token(algorithm: Algorithm, secret: &[u8], claims: &serde_json::Value) -> String {
+        let mut header = Header::new(algorithm);
+        header.kid = Some("test".to_string());
+        jsonwebtoken::encode(&header, claims, &EncodingKey::from_secret(secret)).unwrap()
+    }
+
+    fn rs256_token(claims: &serde_json::Value) -> String {
+        let key = EncodingKey::from_rsa_pem(RSA_KEY).unwrap();
+        jsonwebtoken::encode(&Header::new(Algorithm::RS256), claims, &key).unwrap()
+    }
+
+    fn verifier(conf: &AuthConf) -> Verifier {
+        Verifier::from_conf(conf).unwrap().unwrap()
+    }
+
+    #[test]
+    fn accepts_a_valid_token() {
+        let hs256 = verifier(&hs256_conf());
+        let claims = hs256
+            .verify(&hmac_token(
+                Algorithm::HS256,
+                SECRET,
+                &token_claims(json!({})),
+            ))
+            .unwrap();
+        let principal = hs256.principal(&claims);
+        assert_eq!(principal.subject, "user-1");
+        assert_eq!(principal.roles, vec!["admin"]);
+
+        let rs256 = verifier(&rs256_conf());
+        assert!(rs256.verify(&rs256_token(&token_claims(json!({})))).is_ok());
+    }
+
+    #[test]
+    fn rejects_a_bad_signature() {
+        let token = hmac_token(
+            Algorithm::HS256,
+            b"some-other-secret-with-32-bytes!",
+            &token_claims(json!({})),
+        );
+        assert!(verifier(&hs256_conf()).verify(&token).is_err());
+
+        let token = rs256_token(&token_claims(json!({})));
+        let (unsigned, _) = token.rsplit_once('.').unwrap();
+        let forged = format!("{}.{}", unsigned, "A".repeat(342));
+        assert!(verifier(&rs256_conf()).verify(&forged).is_err());
+    }
+
+    #[test]
+    fn rejects_an_algorithm_other_than_the_pinned_one() {
+        // The public key used as an HMAC secret, the classic algorithm confusion
+        let token = hmac_token(Algorithm::HS256, RSA_PUBLIC_KEY, &token_claims(json!({})));
+        assert!(verifier(&rs256_conf()).verify(&token).is_err());
+
+        let token = hmac_token(Algorithm::HS384, SECRET, &token_claims(json!({})));
+        assert!(verifier(&hs256_conf()).verify(&token).is_err());
+    }
+
+    #[test]
+    fn rejects_an_expired_token() {
+        let expired = token_claims(json!({ "exp": jsonwebtoken::get_current_timestamp() - 3600 }));
+        let token = hmac_token(Algorithm::HS256, SECRET, &expired);
+        assert!(verifier(&hs256_conf()).verify(&token).is_err());
+    }
+
+    #[test]
+    fn rejects_the_wrong_audience_or_issuer() {
+        let verifier = verifier(&AuthConf {
+            audience: vec!["cali".to_string()],
+            issuer: vec!["https://issuer.example".to_string()],
+            ..hs256_conf()
+        });
+        let token = |extra| hmac_token(Algorithm::HS256, SECRET, &token_claims(extra));
+
+        assert!(verifier
+            .verify(&token(
+                json!({ "aud": "cali", "iss": "https://issuer.example" })
+            ))
+            .is_ok());
+        assert!(verifier
+            .verify(&token(
+                json!({ "aud": "other", "iss": "https://issuer.example" })
+            ))
+            .is_err());
+        assert!(verifier
+            .verify(&token(
+                json!({ "aud": "cali", "iss": "https://other.example" })
+            ))
+            .is_err());
+    }
+
+    /// Calls `path` through the layer, returning the status it was answered with, if any, and the
+    /// subject the inner service saw.
+    async fn call(layer: &AuthLayer, path: &str, token: Option<&str>) -> (Option<Code>, String) {
+        let mut service = layer.layer(tower::service_fn(|_: http::Request<()>| async {
+            let subject = get_request_context::<Principal>()
+                .map(|principal| principal.subject)
+                .unwrap_or_default();
+            Ok::<_, Infallible>(http::Response::new(subject))
+        }));
+        let mut request = http::Request::builder().uri(path);
+        if let Some(token) = token {
+            request = request.header("authorization", format!("Bearer {}", token));
+        }
+        let request = request.body(()).unwrap();
+
+        let response = REQUEST_CONTEXT
+            .scope(RequestContext::default(), async move {
+                service.call(request).await
+            })
+            .await
+            .unwrap();
+        let code = Status::from_header_map(response.headers()).map(|status| status.code());
+        (code, response.into_body())
+    }
+
+    #[tokio::test]
+    async fn rejects_protected_methods_and_lets_anonymous_ones_through() {
+        let layer = AuthLayer::new(Some(&hs256_conf()))
+            .unwrap()
+            .allow_anonymous(&["pkg.Public"]);
+        let valid = hmac_token(Algorithm::HS256, SECRET, &token_claims(json!({})));
+        let expired = hmac_token(
+            Algorithm::HS256,
+            SECRET,
+            &token_claims(json!({ "exp": jsonwebtoken::get_current_timestamp() - 3600 })),
+        );
+
+        assert_eq!(
+            call(&layer, "/pkg.Private/Get", Some(&valid)).await,
+            (None, "user-1".to_string())
+        );
+        assert_eq!(
+            call(&layer, "/pkg.Private/Get", None).await.0,
+            Some(Code::Unauthenticated)
+        );
+        assert_eq!(
+            call(&layer, "/pkg.Private/Get", Some(&expired)).await.0,
+            Some(Code::Unauthenticated)
+        );
+        assert_eq!(
+            call(&layer, "/pkg.Public/Get", None).await,
+            (None, String::new())
+        );
+        assert_eq!(
+            call(&layer, "/pkg.Public/Get", Some(&expired)).await,
+            (None, String::new())
+        );
+        assert_eq!(
+            call(&layer, "/pkg.Public/Get", Some(&valid)).await,
+            (None, "user-1".to_string())
+        );
+    }
+}
@@ -0,0 +1,128 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use tonic::Status;
+use tower::{Layer, Service};
+
+use super::status_response;
+use crate::helpers::{get_request_context, split_rpc_path};
+
+/// The authenticated caller, put in the request context by the authentication layers.
+#[derive(Debug, Clone, Default)]
+pub struct Principal {
+    pub subject: String,
+    pub roles: Vec<String>,
+}
+
+impl Principal {
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|own_role| own_role == role)
+    }
+}
+
+/// Returns the authenticated caller of the request currently being served, if there is one.
+pub fn get_principal() -> Option<Principal> {
+    get_request_context::<Principal>()
+}
+
+/// The roles required to call a method, any one of them grants access.
+#[derive(Debug, Clone)]
+pub struct Policy {
+    pub method: String,
+    pub roles: Vec<String>,
+}
+
+impl Policy {
+    /// `method` is written as `package.Service/Method`.
+    pub fn new(method: &str, roles: &[&str]) -> Self {
+        Self {
+            method: method.to_string(),
+            roles: roles.iter().map(|role| role.to_string()).collect(),
+        }
+    }
+}
+
+/// Enforces the `(cali.auth)` roles declared on rpcs before the controller runs. Calls without a
+/// principal are rejected with `Unauthenticated`, principals without any of the roles with
+/// `PermissionDenied`. Methods without a policy pass through. `setup_server!` builds the policies
+/// from your interface protos.
+#[derive(Debug, Clone, Default)]
+pub struct AuthorizationLayer {
+    policies: Arc<HashMap<String, Vec<String>>>,
+}
+
+impl AuthorizationLayer {
+    pub fn new(policies: Vec<Policy>) -> Self {
+        Self {
+            policies: Arc::new(
+                policies
+                    .into_iter()
+                    .filter(|policy| !policy.roles.is_empty())
+                    .map(|policy| (policy.method, policy.roles))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl<S> Layer<S> for AuthorizationLayer {
+    type Service = AuthorizationService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        AuthorizationService {
+            service,
+            policies: self.policies.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AuthorizationService<S> {
+    service: S,
+    policies: Arc<HashMap<String, Vec<String>>>,
+}
+
+impl<S, ReqBody, ResBody> Service<http::Request<ReqBody>> for AuthorizationService<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<ResBody>>,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+    ResBody: Default + Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<ReqBody>) -> Self::Future {
+        let (service, method) = split_rpc_path(request.uri().path());
+        if let Some(roles) = self.policies.get(&format!("{}/{}", service, method)) {
+            let status = match get_principal() {
+                None => Some(Status::unauthenticated(
+                    "This method requires authentication",
+                )),
+                Some(principal) if !roles.iter().any(|role| principal.has_role(role)) => {
+                    Some(Status::permission_denied(format!(
+                        "Requires one of the roles: {}",
+                        roles.join(", ")
+                    )))
+                }
+                Some(_) => None,
+            };
+
+            if let Some(status) = status {
+                return Box::pin(std::future::ready(Ok(status_response(status))));
+            }
+        }
+
+        Box::pin(self.service.call(request))
+    }
+}
@@ -0,0 +1,173 @@
+use std::{
+    any::Any,
+    future::Future,
+    panic::AssertUnwindSafe,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::FutureExt;
+use opentelemetry::{metrics::Counter, KeyValue};
+use tonic::Status;
+use tower::{Layer, Service};
+
+use super::status_response;
+use crate::helpers::split_rpc_path;
+
+/// Catches panics in the controller, or any layer below this one, and answers the call with
+/// `Status::internal` instead of dropping the connection. Panics are logged with the request id
+/// and method, and counted in the `cali.panics` metric.
+#[derive(Debug, Clone, Default)]
+pub struct CatchPanicLayer {
+    panics: Option<Counter<u64>>,
+}
+
+impl CatchPanicLayer {
+    /// A disabled layer lets panics unwind as before.
+    pub fn new(enabled: bool) -> Self {
+        let panics = enabled.then(|| {
+            opentelemetry::global::meter("cali")
+                .u64_counter("cali.panics")
+                .with_description("Panics caught while handling a request")
+                .build()
+        });
+
+        Self { panics }
+    }
+}
+
+impl<S> Layer<S> for CatchPanicLayer {
+    type Service = CatchPanicService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        CatchPanicService {
+            service,
+            panics: self.panics.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CatchPanicService<S> {
+    service: S,
+    panics: Option<Counter<u64>>,
+}
+
+impl<S, ReqBody, ResBody> Service<http::Request<ReqBody>> for CatchPanicService<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<ResBody>>,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+    ResBody: Default + Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<ReqBody>) -> Self::Future {
+        let Some(panics) = self.panics.clone() else {
+            return Box::pin(self.service.call(request));
+        };
+        let path = request.uri().path().to_string();
+
+        let future = match std::panic::catch_unwind(AssertUnwindSafe(|| self.service.call(request)))
+        {
+            Ok(future) => future,
+            Err(panic) => {
+                return Box::pin(std::future::ready(Ok(panic_response(
+                    &panics, &path, panic,
+                ))))
+            }
+        };
+
+        Box::pin(async move {
+            match AssertUnwindSafe(future).catch_unwind().await {
+                Ok(response) => response,
+                Err(panic) => Ok(panic_response(&panics, &path, panic)),
+            }
+        })
+    }
+}
+
+fn panic_response<B: Default>(
+    panics: &Counter<u64>,
+    path: &str,
+    panic: Box<dyn Any + Send>,
+) -> http::Response<B> {
+    let message = panic
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "Box<dyn Any>".to_string());
+    let (service, method) = split_rpc_path(path);
+
+    tracing::error!(
+        rpc.service = service,
+        rpc.method = method,
+        "Handler panicked: {}",
+        message
+    );
+    panics.add(
+        1,
+        &[
+            KeyValue::new("rpc.service", service.to_string()),
+            KeyValue::new("rpc.method", method.to_string()),
+        ],
+    );
+
+    status_response(Status::internal("Internal server error"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use tonic::Code;
+
+    use super::*;
+
+    async fn call<S>(service: &mut S, path: &str) -> (Option<Code>, String)
+    where
+        S: Service<http::Request<()>, Response = http::Response<String>, Error = Infallible>,
+    {
+        let request = http::Request::builder().uri(path).body(()).unwrap();
+        let response = service.call(request).await.unwrap();
+        let code = Status::from_header_map(response.headers()).map(|status| status.code());
+        (code, response.into_body())
+    }
+
+    #[tokio::test]
+    async fn answers_a_panic_with_internal_and_keeps_serving() {
+        // Panics while handling `Panic`, and before returning a future at all for `Call`
+        let mut service =
+            CatchPanicLayer::new(true).layer(tower::service_fn(|request: http::Request<()>| {
+                let path = request.uri().path().to_string();
+                if path == "/pkg.Service/Call" {
+                    panic!("Panicked in call");
+                }
+                async move {
+                    if path == "/pkg.Service/Panic" {
+                        panic!("Panicked in {}", path);
+                    }
+                    Ok(http::Response::new("served".to_string()))
+                }
+            }));
+
+        assert_eq!(
+            call(&mut service, "/pkg.Service/Panic").await,
+            (Some(Code::Internal), String::new())
+        );
+        assert_eq!(
+            call(&mut service, "/pkg.Service/Call").await,
+            (Some(Code::Internal), String::new())
+        );
+        assert_eq!(
+            call(&mut service, "/pkg.Service/Get").await,
+            (None, "served".to_string())
+        );
+    }
+}
@@ -0,0 +1,294 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use http::HeaderMap;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use tonic::Status;
+use tower::{Layer, Service};
+
+use super::status_response;
+use crate::helpers::{insert_request_context, split_rpc_path};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct TimeoutConf {
+    /// Timeout in milliseconds for methods without their own entry, calls can run forever when
+    /// left out.
+    pub default_ms: Option<u64>,
+    /// Timeouts in milliseconds keyed by service (`package.Service`) or method
+    /// (`package.Service/Method`), methods take precedence over services.
+    pub methods: HashMap<String, u64>,
+}
+
+impl TimeoutConf {
+    fn timeout_for(&self, service: &str, method: &str) -> Option<Duration> {
+        self.methods
+            .get(&format!("{}/{}", service, method))
+            .or_else(|| self.methods.get(service))
+            .or(self.default_ms.as_ref())
+            .map(|millis| Duration::from_millis(*millis))
+    }
+}
+
+/// Parses the `grpc-timeout` metadata, at most 8 digits and a unit, e.g. `500m` or `10S`.
+fn grpc_timeout(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get("grpc-timeout")?.to_str().ok()?;
+    if value.len() < 2 || value.len() > 9 {
+        return None;
+    }
+    let (amount, unit) = value.split_at(value.len() - 1);
+    // `parse` would also take a sign
+    if !amount.bytes().all(|digit| digit.is_ascii_digit()) {
+        return None;
+    }
+    let amount: u64 = amount.parse().ok()?;
+
+    match unit {
+        "H" => amount.checked_mul(60 * 60).map(Duration::from_secs),
+        "M" => amount.checked_mul(60).map(Duration::from_secs),
+        "S" => Some(Duration::from_secs(amount)),
+        "m" => Some(Duration::from_millis(amount)),
+        "u" => Some(Duration::from_micros(amount)),
+        "n" => Some(Duration::from_nanos(amount)),
+        _ => None,
+    }
+}
+
+/// The deadline of the request being served, in the request context.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deadline(pub Instant);
+
+/// Enforces the deadline of every call, the shorter of the caller's `grpc-timeout` and the
+/// timeout configured for the method in the `timeouts` config section. Calls that run past it
+/// are dropped and answered with `DeadlineExceeded`. The deadline is available to the handler
+/// through `helpers::get_deadline`, and is respected by `get_conn`.
+#[derive(Debug, Clone, Default)]
+pub struct DeadlineLayer {
+    conf: Arc<TimeoutConf>,
+}
+
+impl DeadlineLayer {
+    pub fn new(conf: Option<&TimeoutConf>) -> Self {
+        Self {
+            conf: Arc::new(conf.cloned().unwrap_or_default()),
+        }
+    }
+}
+
+impl<S> Layer<S> for DeadlineLayer {
+    type Service = DeadlineService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        DeadlineService {
+            service,
+            conf: self.conf.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DeadlineService<S> {
+    service: S,
+    conf: Arc<TimeoutConf>,
+}
+
+impl<S, ReqBody, ResBody> Service<http::Request<ReqBody>> for DeadlineService<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<ResBody>>,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+    ResBody: Default + Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<ReqBody>) -> Self::Future {
+        let (service, method) = split_rpc_path(request.uri().path());
+        // Tonic also times out on `grpc-timeout` around the whole stack, but answers with
+        // `Cancelled`. Rounding down to the millisecond makes sure ours fires first.
+        let caller_timeout = grpc_timeout(request.headers())
+            .map(|timeout| Duration::from_millis(timeout.as_millis().saturating_sub(1) as u64));
+        let timeout = match (caller_timeout, self.conf.timeout_for(service, method)) {
+            (Some(caller), Some(configured)) => Some(caller.min(configured)),
+            (caller, configured) => caller.or(configured),
+        };
+        let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+        if let Some(deadline) = deadline {
+            let _ = insert_request_context(Deadline(deadline));
+        }
+
+        let future = self.service.call(request);
+        let Some(deadline) = deadline else {
+            return Box::pin(future);
+        };
+
+        Box::pin(async move {
+            match tokio::time::timeout_at(deadline, future).await {
+                Ok(response) => response,
+                Err(_) => Ok(status_response(Status::deadline_exceeded(
+                    "Deadline exceeded",
+                ))),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use tonic::Code;
+
+    use super::*;
+    use crate::{helpers::get_request_context, REQUEST_CONTEXT};
+
+    fn timeout_of(value: &str) -> Option<Duration> {
+        let mut headers = HeaderMap::new();
+        headers.insert("grpc-timeout", value.parse().unwrap());
+        grpc_timeout(&headers)
+    }
+
+    #[test]
+    fn parses_every_grpc_timeout_unit() {
+        assert_eq!(timeout_of("2H"), Some(Duration::from_secs(2 * 60 * 60)));
+        assert_eq!(timeout_of("3M"), Some(Duration::from_secs(3 * 60)));
+        assert_eq!(timeout_of("10S"), Some(Duration::from_secs(10)));
+        assert_eq!(timeout_of("500m"), Some(Duration::from_millis(500)));
+        assert_eq!(timeout_of("250u"), Some(Duration::from_micros(250)));
+        assert_eq!(timeout_of("100n"), Some(Duration::from_nanos(100)));
+        assert_eq!(grpc_timeout(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn ignores_malformed_grpc_timeouts() {
+        for value in [
+            "S",
+            "5",
+            "10s",
+            "10x",
+            "abcS",
+            "-1S",
+            "+1S",
+            "1.5S",
+            " 1S",
+            "123456789S",
+        ] {
+            assert_eq!(timeout_of(value), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn parses_the_largest_grpc_timeouts_without_overflow() {
+        assert_eq!(
+            timeout_of("99999999H"),
+            Some(Duration::from_secs(99_999_999 * 60 * 60))
+        );
+        assert_eq!(
+            timeout_of("99999999n"),
+            Some(Duration::from_nanos(99_999_999))
+        );
+    }
+
+    /// Calls `path` through the layer with an inner service that takes `delay`, returning the
+    /// status it was answered with, if any, and the time until the deadline the service saw.
+    async fn call(
+        conf: Option<&TimeoutConf>,
+        path: &str,
+        grpc_timeout: Option<&str>,
+        delay: Duration,
+    ) -> (Option<Code>, Option<Duration>) {
+        let mut service = DeadlineLayer::new(conf).layer(tower::service_fn(
+            move |_: http::Request<()>| async move {
+                let remaining = get_request_context::<Deadline>()
+                    .map(|Deadline(deadline)| deadline - Instant::now());
+                tokio::time::sleep(delay).await;
+                Ok::<_, Infallible>(http::Response::new(remaining))
+            },
+        ));
+        let mut request = http::Request::builder().uri(path);
+        if let Some(grpc_timeout) = grpc_timeout {
+            request = request.header("grpc-timeout", grpc_timeout);
+        }
+        let request = request.body(()).unwrap();
+
+        let response = REQUEST_CONTEXT
+            .scope(
+                Default::default(),
+                async move { service.call(request).await },
+            )
+            .await
+            .unwrap();
+        let code = Status::from_header_map(response.headers()).map(|status| status.code());
+        (code, response.into_body())
+    }
+
+    #[tokio::test]
+    async fn answers_with_deadline_exceeded_once_the_caller_timeout_passes() {
+        let started = Instant::now();
+        let (code, _) = call(
+            None,
+            "/pkg.Service/Get",
+            Some("50m"),
+            Duration::from_secs(5),
+        )
+        .await;
+
+        assert_eq!(code, Some(Code::DeadlineExceeded));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn takes_the_shorter_of_the_caller_and_configured_timeouts() {
+        let conf = TimeoutConf {
+            default_ms: Some(60_000),
+            methods: HashMap::from([
+                ("pkg.Service".to_string(), 30_000),
+                ("pkg.Service/Fast".to_string(), 100),
+            ]),
+        };
+        let remaining = |(code, remaining): (Option<Code>, Option<Duration>)| {
+            assert_eq!(code, None);
+            remaining.unwrap()
+        };
+
+        let fast = remaining(call(Some(&conf), "/pkg.Service/Fast", None, Duration::ZERO).await);
+        assert!(fast <= Duration::from_millis(100));
+        let service = remaining(call(Some(&conf), "/pkg.Service/Get", None, Duration::ZERO).await);
+        assert!(service > Duration::from_secs(29) && service <= Duration::from_secs(30));
+        let other = remaining(call(Some(&conf), "/pkg.Other/Get", None, Duration::ZERO).await);
+        assert!(other > Duration::from_secs(59));
+        let caller =
+            remaining(call(Some(&conf), "/pkg.Service/Get", Some("2S"), Duration::ZERO).await);
+        assert!(caller < Duration::from_secs(2));
+
+        let (code, _) = call(
+            Some(&conf),
+            "/pkg.Service/Fast",
+            None,
+            Duration::from_secs(5),
+        )
+        .await;
+        assert_eq!(code, Some(Code::DeadlineExceeded));
+    }
+
+    #[tokio::test]
+    async fn leaves_calls_without_a_timeout_alone() {
+        let (code, remaining) =
+            call(None, "/pkg.Service/Get", None, Duration::from_millis(50)).await;
+
+        assert_eq!(code, None);
+        assert_eq!(remaining, None);
+    }
+}
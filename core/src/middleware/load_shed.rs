@@ -0,0 +1,119 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use tonic::{transport::server::TcpConnectInfo, Status};
+use tower::{Layer, Service};
+
+use super::status_response;
+
+type InFlight = Arc<Mutex<HashMap<SocketAddr, usize>>>;
+
+/// Rejects calls with `Unavailable` once a connection has `limit` calls in flight, instead of
+/// letting them queue. Enabled with `load-shed` in the `server` config section. Connections are
+/// told apart by their remote address, calls without a `TcpConnectInfo`, like those coming in over
+/// a Unix socket, aren't limited.
+#[derive(Debug, Clone, Default)]
+pub struct LoadShedLayer {
+    limit: Option<usize>,
+    in_flight: InFlight,
+}
+
+impl LoadShedLayer {
+    pub fn new(limit: Option<usize>) -> Self {
+        Self {
+            limit,
+            in_flight: InFlight::default(),
+        }
+    }
+}
+
+impl<S> Layer<S> for LoadShedLayer {
+    type Service = LoadShedService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        LoadShedService {
+            service,
+            limit: self.limit,
+            in_flight: self.in_flight.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LoadShedService<S> {
+    service: S,
+    limit: Option<usize>,
+    in_flight: InFlight,
+}
+
+/// Counts a call as in flight on its connection until dropped.
+struct Permit {
+    connection: SocketAddr,
+    in_flight: InFlight,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some(count) = in_flight.get_mut(&self.connection) {
+            *count -= 1;
+            if *count == 0 {
+                in_flight.remove(&self.connection);
+            }
+        }
+    }
+}
+
+impl<S, ReqBody, ResBody> Service<http::Request<ReqBody>> for LoadShedService<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<ResBody>>,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+    ResBody: Default + Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<ReqBody>) -> Self::Future {
+        // The remote address and port identify the connection the call came in on
+        let connection = request
+            .extensions()
+            .get::<TcpConnectInfo>()
+            .and_then(|info| info.remote_addr());
+        let (Some(limit), Some(connection)) = (self.limit, connection) else {
+            return Box::pin(self.service.call(request));
+        };
+        {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            let count = in_flight.entry(connection).or_default();
+            if *count >= limit {
+                let status = Status::unavailable("Too many concurrent calls on this connection");
+                return Box::pin(std::future::ready(Ok(status_response(status))));
+            }
+            *count += 1;
+        }
+
+        let permit = Permit {
+            connection,
+            in_flight: self.in_flight.clone(),
+        };
+        let future = self.service.call(request);
+
+        Box::pin(async move {
+            let response = future.await;
+            drop(permit);
+            response
+        })
+    }
+}
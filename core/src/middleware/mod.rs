@@ -1 +1,26 @@
+use http::HeaderValue;
+
+pub mod access_log;
+pub mod api_key;
+pub mod auth;
+pub mod authorization;
+pub mod catch_panic;
+pub mod deadline;
+pub mod load_shed;
+pub mod rate_limit;
+pub mod request_context;
+pub mod request_id;
 pub mod server_context;
+pub mod telemetry;
+
+/// Builds a trailers-only gRPC response carrying `status`, for layers that reject a request before
+/// it reaches the controller.
+pub fn status_response<B: Default>(status: tonic::Status) -> http::Response<B> {
+    let mut response = http::Response::new(B::default());
+    response.headers_mut().insert(
+        http::header::CONTENT_TYPE,
+        HeaderValue::from_static("application/grpc"),
+    );
+    let _ = status.add_header(response.headers_mut());
+    response
+}
@@ -0,0 +1,286 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tonic::{metadata::MetadataValue, transport::server::TcpConnectInfo, Status};
+use tower::{Layer, Service};
+
+use super::{authorization::get_principal, status_response};
+use crate::helpers::split_rpc_path;
+
+/// Buckets are pruned once there are more than this many, only full buckets are dropped.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct RateLimitConf {
+    /// Every matching rule has to have a token left for a call to go through.
+    pub rules: Vec<RateLimitRule>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RateLimitRule {
+    /// A service (`package.Service`) or method (`package.Service/Method`), every call when left
+    /// out.
+    #[serde(default)]
+    pub method: Option<String>,
+    /// Who shares a bucket, defaults to everyone.
+    #[serde(default)]
+    pub key: RateLimitKey,
+    /// Tokens added to the bucket per second.
+    pub per_second: f64,
+    /// The size of the bucket, defaults to `per-second`.
+    #[serde(default)]
+    pub burst: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RateLimitKey {
+    /// One bucket for all callers.
+    #[default]
+    Global,
+    /// A bucket per peer IP address.
+    Peer,
+    /// A bucket per authenticated subject, from an API key or JWT. Anonymous callers fall back to
+    /// their peer IP address.
+    Principal,
+}
+
+impl RateLimitRule {
+    fn matches(&self, service: &str, method: &str) -> bool {
+        match self.method.as_deref().map(|entry| entry.split_once('/')) {
+            None => true,
+            Some(Some((rule_service, rule_method))) => {
+                rule_service == service && rule_method == method
+            }
+            Some(None) => self.method.as_deref() == Some(service),
+        }
+    }
+
+    fn capacity(&self) -> f64 {
+        self.burst.unwrap_or(self.per_second).max(1.0)
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, rule: &RateLimitRule, now: Instant) {
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rule.per_second).min(rule.capacity());
+        self.updated_at = now;
+    }
+}
+
+struct Limiter {
+    rules: Vec<RateLimitRule>,
+    buckets: Mutex<HashMap<(usize, String), Bucket>>,
+}
+
+impl Limiter {
+    /// Takes a token from every matching rule's bucket. Returns how long to wait when one of them
+    /// is empty, in which case no tokens are taken.
+    fn acquire(&self, service: &str, method: &str, identity: &Identity) -> Option<Duration> {
+        let now = Instant::now();
+        let keys: Vec<(usize, String)> = self
+            .rules
+            .iter()
+            .enumerate()
+            .filter(|(_, rule)| rule.matches(service, method))
+            .map(|(index, rule)| (index, identity.key(rule.key)))
+            .collect();
+        if keys.is_empty() {
+            return None;
+        }
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > PRUNE_THRESHOLD {
+            buckets.retain(|(index, _), bucket| {
+                bucket.refill(&self.rules[*index], now);
+                bucket.tokens < self.rules[*index].capacity()
+            });
+        }
+
+        let mut wait = Duration::ZERO;
+        for key in keys.iter() {
+            let rule = &self.rules[key.0];
+            let bucket = buckets.entry(key.clone()).or_insert(Bucket {
+                tokens: rule.capacity(),
+                updated_at: now,
+            });
+            bucket.refill(rule, now);
+            if bucket.tokens < 1.0 {
+                wait = wait.max(Duration::from_secs_f64(
+                    (1.0 - bucket.tokens) / rule.per_second,
+                ));
+            }
+        }
+        if !wait.is_zero() {
+            return Some(wait);
+        }
+
+        for key in keys.iter() {
+            if let Some(bucket) = buckets.get_mut(key) {
+                bucket.tokens -= 1.0;
+            }
+        }
+
+        None
+    }
+}
+
+struct Identity {
+    peer: String,
+    subject: Option<String>,
+}
+
+impl Identity {
+    fn key(&self, key: RateLimitKey) -> String {
+        match key {
+            RateLimitKey::Global => String::new(),
+            RateLimitKey::Peer => self.peer.clone(),
+            RateLimitKey::Principal => match &self.subject {
+                Some(subject) => format!("subject:{}", subject),
+                None => format!("peer:{}", self.peer),
+            },
+        }
+    }
+}
+
+/// Applies the token bucket limits of the `rate-limit` config section. Calls over the limit are
+/// rejected with `ResourceExhausted` and a `retry-after` metadata entry holding the seconds to
+/// wait. Runs after authentication, so limits can be keyed by the caller's principal.
+#[derive(Clone, Default)]
+pub struct RateLimitLayer {
+    limiter: Option<Arc<Limiter>>,
+}
+
+impl RateLimitLayer {
+    pub fn new(conf: Option<&RateLimitConf>) -> Result<Self, String> {
+        let Some(conf) = conf.filter(|conf| !conf.rules.is_empty()) else {
+            return Ok(Self::default());
+        };
+        if let Some(rule) = conf.rules.iter().find(|rule| rule.per_second <= 0.0) {
+            return Err(format!(
+                "Rate limit per-second has to be positive, got {}",
+                rule.per_second
+            ));
+        }
+
+        Ok(Self {
+            limiter: Some(Arc::new(Limiter {
+                rules: conf.rules.clone(),
+                buckets: Mutex::new(HashMap::new()),
+            })),
+        })
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimitService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        RateLimitService {
+            service,
+            limiter: self.limiter.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimitService<S> {
+    service: S,
+    limiter: Option<Arc<Limiter>>,
+}
+
+impl<S, ReqBody, ResBody> Service<http::Request<ReqBody>> for RateLimitService<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<ResBody>>,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+    ResBody: Default + Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<ReqBody>) -> Self::Future {
+        let Some(limiter) = &self.limiter else {
+            return Box::pin(self.service.call(request));
+        };
+
+        let (service, method) = split_rpc_path(request.uri().path());
+        let identity = Identity {
+            peer: request
+                .extensions()
+                .get::<TcpConnectInfo>()
+                .and_then(|info| info.remote_addr())
+                .map(|addr| addr.ip().to_string())
+                .unwrap_or_default(),
+            subject: get_principal().map(|principal| principal.subject),
+        };
+
+        if let Some(wait) = limiter.acquire(service, method, &identity) {
+            let mut status = Status::resource_exhausted("Rate limit exceeded");
+            let retry_after = wait.as_secs_f64().ceil() as u64;
+            status
+                .metadata_mut()
+                .insert("retry-after", MetadataValue::from(retry_after));
+            return Box::pin(std::future::ready(Ok(status_response(status))));
+        }
+
+        Box::pin(self.service.call(request))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_bucket_reports_wait_until_next_token() {
+        let limiter = Limiter {
+            rules: vec![RateLimitRule {
+                method: Some("pkg.Service/Method".to_string()),
+                key: RateLimitKey::Peer,
+                per_second: 1.0,
+                burst: Some(2.0),
+            }],
+            buckets: Mutex::new(HashMap::new()),
+        };
+        let identity = Identity {
+            peer: "10.0.0.1".to_string(),
+            subject: None,
+        };
+        let other = Identity {
+            peer: "10.0.0.2".to_string(),
+            subject: None,
+        };
+
+        assert!(limiter
+            .acquire("pkg.Service", "Method", &identity)
+            .is_none());
+        assert!(limiter
+            .acquire("pkg.Service", "Method", &identity)
+            .is_none());
+        let wait = limiter.acquire("pkg.Service", "Method", &identity).unwrap();
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1));
+        assert!(limiter.acquire("pkg.Service", "Method", &other).is_none());
+        assert!(limiter.acquire("pkg.Service", "Other", &identity).is_none());
+    }
+}
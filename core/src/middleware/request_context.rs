@@ -0,0 +1,94 @@
+use std::{
+    any::TypeId,
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    task::{Context, Poll},
+};
+
+use tokio::task::futures::TaskLocalFuture;
+use tower::{Layer, Service};
+
+use crate::{MapKey, REQUEST_CONTEXT};
+
+/// Values scoped to a single request, keyed by type. Layers put things like the request ID, the
+/// deadline and the caller in it, and anything serving the request reads them back through
+/// `helpers::get_request_context`. Clones share the same values.
+#[derive(Debug, Clone, Default)]
+pub struct RequestContext {
+    values: Arc<RwLock<HashMap<TypeId, MapKey>>>,
+}
+
+impl RequestContext {
+    /// Stores `value`, replacing an earlier value of the same type.
+    pub fn insert<T: Send + Sync + 'static>(&self, value: T) {
+        self.values
+            .write()
+            .unwrap()
+            .insert(TypeId::of::<T>(), Arc::new(value));
+    }
+
+    pub fn get<T: Clone + 'static>(&self) -> Option<T> {
+        self.values
+            .read()
+            .unwrap()
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref::<T>())
+            .cloned()
+    }
+
+    pub fn contains<T: 'static>(&self) -> bool {
+        self.values.read().unwrap().contains_key(&TypeId::of::<T>())
+    }
+
+    pub fn remove<T: 'static>(&self) {
+        self.values.write().unwrap().remove(&TypeId::of::<T>());
+    }
+
+    /// A copy of the values that isn't shared with this context, values inserted or removed
+    /// afterwards in either one aren't seen by the other.
+    pub fn detached(&self) -> Self {
+        Self {
+            values: Arc::new(RwLock::new(self.values.read().unwrap().clone())),
+        }
+    }
+}
+
+/// Gives every request an empty `RequestContext`. It has to wrap every layer that reads or writes
+/// the request context, so it's the outermost layer of the cali stack.
+#[derive(Debug, Clone, Default)]
+pub struct RequestContextLayer;
+
+impl<S> Layer<S> for RequestContextLayer {
+    type Service = RequestContextService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        RequestContextService { service }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RequestContextService<S> {
+    service: S,
+}
+
+impl<S, Request> Service<Request> for RequestContextService<S>
+where
+    S: Service<Request>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = TaskLocalFuture<RequestContext, S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let context = RequestContext::default();
+
+        // Inner layers fill the context while setting up their futures as well
+        let future = REQUEST_CONTEXT.sync_scope(context.clone(), || self.service.call(request));
+
+        REQUEST_CONTEXT.scope(context, future)
+    }
+}
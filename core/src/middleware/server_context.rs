@@ -10,17 +10,36 @@ use tower::{Layer, Service};
 
 use crate::{MapKey, SERVER_CONTEXT};
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct ServerContextLayer<
     T: 'static + Send + Sync,
     I: 'static + Send + Sync,
     C: 'static + Send + Sync,
 > {
     pub extendable_context: Option<Arc<T>>,
+    /// Added with `CaliConfig::provide`.
+    pub provided: HashMap<TypeId, MapKey>,
     pub internal_context: Arc<I>,
     pub config: Arc<C>,
 } // Internal + a open struct for other people
 
+// Derived Clone would require the contexts themselves to be Clone
+impl<T, I, C> Clone for ServerContextLayer<T, I, C>
+where
+    T: 'static + Send + Sync,
+    I: 'static + Send + Sync,
+    C: 'static + Send + Sync,
+{
+    fn clone(&self) -> Self {
+        Self {
+            extendable_context: self.extendable_context.clone(),
+            provided: self.provided.clone(),
+            internal_context: self.internal_context.clone(),
+            config: self.config.clone(),
+        }
+    }
+}
+
 impl<S, T, I, C> Layer<S> for ServerContextLayer<T, I, C>
 where
     T: 'static + Send + Sync,
@@ -30,16 +49,28 @@ where
     type Service = ServerContextService<S>;
 
     fn layer(&self, service: S) -> Self::Service {
-        let mut context: HashMap<TypeId, MapKey> = HashMap::new();
+        ServerContextService {
+            service,
+            context: Arc::new(self.context()),
+        }
+    }
+}
+
+impl<T, I, C> ServerContextLayer<T, I, C>
+where
+    T: 'static + Send + Sync,
+    I: 'static + Send + Sync,
+    C: 'static + Send + Sync,
+{
+    /// The map every request is served with.
+    pub fn context(&self) -> HashMap<TypeId, MapKey> {
+        let mut context = self.provided.clone();
         if let Some(extendable_context) = &self.extendable_context {
             context.insert(TypeId::of::<T>(), extendable_context.clone());
         }
         context.insert(TypeId::of::<I>(), self.internal_context.clone());
         context.insert(TypeId::of::<C>(), self.config.clone());
-        ServerContextService {
-            service,
-            context: Arc::new(context),
-        }
+        context
     }
 }
 
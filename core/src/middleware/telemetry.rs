@@ -0,0 +1,119 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use http::HeaderMap;
+use opentelemetry::propagation::Extractor;
+use tonic::transport::server::TcpConnectInfo;
+use tower::{Layer, Service};
+use tracing::{field::Empty, Instrument};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::helpers::{get_request_id, split_rpc_path};
+
+/// Opens a `grpc.request` span around every RPC. The span's parent is taken from the
+/// `traceparent`/`tracestate` metadata of the incoming request, so calls can be followed across
+/// services.
+#[derive(Debug, Clone, Default)]
+pub struct TelemetryLayer;
+
+impl<S> Layer<S> for TelemetryLayer {
+    type Service = TelemetryService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        TelemetryService { service }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TelemetryService<S> {
+    service: S,
+}
+
+impl<S, ReqBody, ResBody> Service<http::Request<ReqBody>> for TelemetryService<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<ReqBody>) -> Self::Future {
+        let (service, method) = split_rpc_path(request.uri().path());
+        let peer = request
+            .extensions()
+            .get::<TcpConnectInfo>()
+            .and_then(|info| info.remote_addr())
+            .map(|addr| addr.to_string())
+            .unwrap_or_default();
+
+        let span = tracing::info_span!(
+            "grpc.request",
+            otel.name = %request.uri().path(),
+            otel.kind = "server",
+            otel.status_code = Empty,
+            rpc.system = "grpc",
+            rpc.service = %service,
+            rpc.method = %method,
+            rpc.grpc.status_code = Empty,
+            net.peer.addr = %peer,
+            request_id = %get_request_id().unwrap_or_default(),
+        );
+        let parent = opentelemetry::global::get_text_map_propagator(|propagator| {
+            propagator.extract(&MetadataExtractor(request.headers()))
+        });
+        span.set_parent(parent);
+
+        let future = {
+            let _entered = span.enter();
+            self.service.call(request)
+        };
+
+        Box::pin(
+            async move {
+                let result = future.await;
+                let span = tracing::Span::current();
+                match &result {
+                    Ok(response) => {
+                        // Errors are sent trailers-only, so a missing header means the handler
+                        // succeeded and the OK status follows in the trailers.
+                        let code = response
+                            .headers()
+                            .get("grpc-status")
+                            .and_then(|code| code.to_str().ok())
+                            .and_then(|code| code.parse::<i32>().ok())
+                            .unwrap_or(0);
+                        span.record("rpc.grpc.status_code", code);
+                        if code != 0 {
+                            span.record("otel.status_code", "ERROR");
+                        }
+                    }
+                    Err(_) => {
+                        span.record("otel.status_code", "ERROR");
+                    }
+                }
+                result
+            }
+            .instrument(span),
+        )
+    }
+}
+
+struct MetadataExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for MetadataExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
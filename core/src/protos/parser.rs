@@ -9,14 +9,67 @@ pub struct ProtoData {
 #[derive(Debug)]
 pub struct ProtoService {
     pub name: String,
+    pub package: String,
     pub rpcs: Vec<ProtoRPC>,
 }
 
+impl ProtoService {
+    /// The fully qualified service name as it appears in request paths, e.g. `package.Service`.
+    pub fn full_name(&self) -> String {
+        if self.package.is_empty() {
+            self.name.clone()
+        } else {
+            format!("{}.{}", self.package, self.name)
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ProtoRPC {
     pub name: String,
     pub request_name: String,
     pub response_name: String,
+    pub auth: Option<ProtoAuthRule>,
+}
+
+/// The `(cali.auth)` option of an rpc, e.g. `option (cali.auth) = { roles: ["admin"] };`
+#[derive(Debug, Default)]
+pub struct ProtoAuthRule {
+    pub roles: Vec<String>,
+    pub allow_anonymous: bool,
+}
+
+#[derive(Debug)]
+pub struct ProtoMessage {
+    pub name: String,
+    pub package: String,
+    /// The messages this one is nested in, outermost first.
+    pub parents: Vec<String>,
+    pub fields: Vec<ProtoField>,
+}
+
+impl ProtoMessage {
+    /// The fully qualified message name, e.g. `package.Outer.Inner`.
+    pub fn full_name(&self) -> String {
+        self.package
+            .split('.')
+            .filter(|part| !part.is_empty())
+            .chain(self.parents.iter().map(|parent| parent.as_str()))
+            .chain([self.name.as_str()])
+            .collect::<Vec<&str>>()
+            .join(".")
+    }
+}
+
+#[derive(Debug)]
+pub struct ProtoField {
+    pub name: String,
+    pub number: u32,
+    /// The declared type, e.g. `string`, `int64` or `HelloRequest`.
+    pub kind: String,
+    pub repeated: bool,
+    /// Marked with `[(cali.sensitive) = true]`, its value is redacted in logs.
+    pub sensitive: bool,
 }
 
 pub fn get_proto_data(service_root: &Path) -> Result<ProtoData, String> {
@@ -25,6 +78,7 @@ pub fn get_proto_data(service_root: &Path) -> Result<ProtoData, String> {
     let rpc_capture_regex =
         Regex::new(r"\s*rpc\s+(?P<name>\w+)\s+\((?P<req>\w+)\)\s+returns\s+\((?P<resp>\w+)\)")
             .unwrap();
+    let package_capture_regex = Regex::new(r"package\s+(?P<package>[\w.]+)\s*;").unwrap();
     let service_files: Vec<String> = fs::read_dir(service_root)
         .expect("Could not read contents of interface directory")
         .filter(|entry| entry.is_ok())
@@ -33,19 +87,29 @@ pub fn get_proto_data(service_root: &Path) -> Result<ProtoData, String> {
 
     let mut proto_services = Vec::new();
     for service_file in service_files.into_iter() {
-        let service_path = format!("{}", service_file);
+        let service_path = service_file.to_string();
         let service = fs::read_to_string(service_path).expect("Could not read service root");
+        // A commented out rpc or `(cali.auth)` option must not be picked up
+        let service = strip_comments(&service);
+        let package = package_capture_regex
+            .captures(&service)
+            .map(|cap| cap["package"].to_string())
+            .unwrap_or_default();
         for service_cap in service_capture_regex.captures_iter(&service[..]) {
+            let content = &service_cap["content"];
             let mut proto_calls = Vec::new();
-            for rpc_cap in rpc_capture_regex.captures_iter(&service_cap["content"]) {
+            for rpc_cap in rpc_capture_regex.captures_iter(content) {
+                let rpc_end = rpc_cap.get(0).unwrap().end();
                 proto_calls.push(ProtoRPC {
                     name: rpc_cap["name"].to_string(),
                     request_name: rpc_cap["req"].to_string(),
                     response_name: rpc_cap["resp"].to_string(),
+                    auth: rpc_body(&content[rpc_end..]).and_then(parse_auth_rule),
                 });
             }
             proto_services.push(ProtoService {
                 name: service_cap["name"].to_string(),
+                package: package.clone(),
                 rpcs: proto_calls,
             });
         }
@@ -55,3 +119,263 @@ pub fn get_proto_data(service_root: &Path) -> Result<ProtoData, String> {
         services: proto_services,
     })
 }
+
+/// Parses the messages declared in every proto file in `roots`, roots that don't exist are
+/// skipped. Nested messages are listed separately, with the messages they're nested in.
+pub fn get_proto_messages(roots: &[&Path]) -> Vec<ProtoMessage> {
+    let message_regex = Regex::new(r"\bmessage\s+(?P<name>\w+)\s*").unwrap();
+    let package_regex = Regex::new(r"package\s+(?P<package>[\w.]+)\s*;").unwrap();
+
+    let mut messages = Vec::new();
+    for root in roots.iter().filter(|root| root.is_dir()) {
+        let proto_files = fs::read_dir(root)
+            .expect("Could not read contents of interface directory")
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|extension| extension == "proto")
+            });
+
+        for proto_file in proto_files {
+            let proto = fs::read_to_string(&proto_file).expect("Could not read proto file");
+            let proto = strip_comments(&proto);
+            let package = package_regex
+                .captures(&proto)
+                .map(|cap| cap["package"].to_string())
+                .unwrap_or_default();
+            // The messages around the current one, with where their bodies end
+            let mut open: Vec<(String, usize)> = Vec::new();
+            for message_cap in message_regex.captures_iter(&proto) {
+                let declaration = message_cap.get(0).unwrap();
+                let rest = &proto[declaration.end()..];
+                if let Some(body) = rpc_body(rest) {
+                    open.retain(|(_, end)| *end > declaration.start());
+                    // `rpc_body` skips the whitespace and opening brace in front of the body
+                    let end =
+                        declaration.end() + rest.len() - rest.trim_start().len() + 1 + body.len();
+                    messages.push(ProtoMessage {
+                        name: message_cap["name"].to_string(),
+                        package: package.clone(),
+                        parents: open.iter().map(|(name, _)| name.clone()).collect(),
+                        fields: parse_fields(&own_fields(body)),
+                    });
+                    open.push((message_cap["name"].to_string(), end));
+                }
+            }
+        }
+    }
+
+    messages
+}
+
+/// Removes `//` comments, leaving the line breaks in place.
+fn strip_comments(proto: &str) -> String {
+    Regex::new(r"//[^\n]*")
+        .unwrap()
+        .replace_all(proto, "")
+        .into_owned()
+}
+
+/// Drops nested message and enum declarations from a message body, keeping `oneof` members since
+/// they are fields of the message itself.
+fn own_fields(body: &str) -> String {
+    let mut own = String::new();
+    let mut hidden_depth = 0;
+    let mut oneof_depth = 0;
+    let mut statement_start = 0;
+    for (index, character) in body.char_indices() {
+        match character {
+            '{' if hidden_depth == 0
+                && body[statement_start..index].trim().starts_with("oneof") =>
+            {
+                oneof_depth += 1;
+                statement_start = index + 1;
+                continue;
+            }
+            '{' => hidden_depth += 1,
+            '}' if hidden_depth > 0 => {
+                hidden_depth -= 1;
+                statement_start = index + 1;
+                continue;
+            }
+            '}' if oneof_depth > 0 => {
+                oneof_depth -= 1;
+                statement_start = index + 1;
+                continue;
+            }
+            ';' => statement_start = index + 1,
+            _ => (),
+        }
+        if hidden_depth == 0 {
+            own.push(character);
+        }
+    }
+
+    own
+}
+
+fn parse_fields(body: &str) -> Vec<ProtoField> {
+    let field_regex = Regex::new(
+        r"(?:(?P<label>repeated|optional|required)\s+)?(?P<kind>map\s*<[^>]*>|[\w.]+)\s+(?P<name>\w+)\s*=\s*(?P<number>\d+)\s*(?:\[(?P<options>[^\]]*)\])?\s*;",
+    )
+    .unwrap();
+    let sensitive_regex = Regex::new(r"\(\s*cali\.sensitive\s*\)\s*=\s*true").unwrap();
+
+    field_regex
+        .captures_iter(body)
+        .filter(|field_cap| &field_cap["kind"] != "option")
+        .map(|field_cap| ProtoField {
+            name: field_cap["name"].to_string(),
+            number: field_cap["number"].parse().unwrap_or_default(),
+            kind: field_cap["kind"].to_string(),
+            repeated: field_cap.name("label").map(|label| label.as_str()) == Some("repeated")
+                || field_cap["kind"].starts_with("map"),
+            sensitive: field_cap
+                .name("options")
+                .is_some_and(|options| sensitive_regex.is_match(options.as_str())),
+        })
+        .collect()
+}
+
+/// Returns the `{ ... }` options block following an rpc declaration, if it has one.
+fn rpc_body(rest: &str) -> Option<&str> {
+    let rest = rest.trim_start();
+    if !rest.starts_with('{') {
+        return None;
+    }
+
+    let mut depth = 0;
+    for (index, character) in rest.char_indices() {
+        match character {
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(&rest[1..index]);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    None
+}
+
+fn parse_auth_rule(body: &str) -> Option<ProtoAuthRule> {
+    let option_regex =
+        Regex::new(r"option\s*\(\s*cali\.auth\s*\)\s*=\s*\{(?P<rule>[^}]*)\}").unwrap();
+    let roles_regex = Regex::new(r#"roles\s*:\s*(?P<roles>\[[^\]]*\]|"[^"]*")"#).unwrap();
+    let role_regex = Regex::new(r#""(?P<role>[^"]*)""#).unwrap();
+    let anonymous_regex = Regex::new(r"allow_anonymous\s*:\s*true").unwrap();
+
+    let captures = option_regex.captures(body)?;
+    let rule = &captures["rule"];
+    let roles = roles_regex
+        .captures_iter(rule)
+        .flat_map(|roles_cap| {
+            role_regex
+                .captures_iter(roles_cap.name("roles").unwrap().as_str())
+                .map(|role_cap| role_cap["role"].to_string())
+                .collect::<Vec<String>>()
+        })
+        .collect();
+
+    Some(ProtoAuthRule {
+        roles,
+        allow_anonymous: anonymous_regex.is_match(rule),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_auth_option_from_rpc_body() {
+        let body = rpc_body(
+            " {\n    option (cali.auth) = { roles: [\"admin\", \"ops\"] allow_anonymous: false };\n  }\n",
+        )
+        .unwrap();
+        let rule = parse_auth_rule(body).unwrap();
+
+        assert_eq!(rule.roles, vec!["admin", "ops"]);
+        assert!(!rule.allow_anonymous);
+        assert!(rpc_body(";\n  rpc Other (A) returns (B);").is_none());
+    }
+
+    #[test]
+    fn ignores_commented_out_auth_options() {
+        let root = std::env::temp_dir().join(format!("cali-parser-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(
+            root.join("accounts.proto"),
+            "package accounts;\n\nservice Accounts {\n  rpc Get (GetRequest) returns (Account) {\n    // option (cali.auth) = { roles: [\"admin\"] };\n  }\n  // rpc Gone (GetRequest) returns (Account);\n  rpc List (ListRequest) returns (Accounts) {\n    option (cali.auth) = { roles: [\"ops\"] }; // not [\"admin\"]\n  }\n}\n",
+        )
+        .unwrap();
+
+        let data = get_proto_data(&root).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+        let rpcs = &data.services[0].rpcs;
+
+        assert_eq!(rpcs.len(), 2);
+        assert_eq!(rpcs[0].name, "Get");
+        assert!(rpcs[0].auth.is_none());
+        assert_eq!(rpcs[1].auth.as_ref().unwrap().roles, vec!["ops"]);
+    }
+
+    #[test]
+    fn names_messages_with_their_package_and_parents() {
+        let root = std::env::temp_dir().join(format!("cali-messages-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(
+            root.join("accounts.proto"),
+            "package app.accounts;\n\nmessage Outer {\n  message Inner {\n    message Deepest {}\n  }\n  Inner inner = 1;\n}\n\nmessage Request {\n  string id = 1;\n}\n",
+        )
+        .unwrap();
+
+        let messages = get_proto_messages(&[&root]);
+        fs::remove_dir_all(&root).unwrap();
+        let names: Vec<String> = messages.iter().map(|message| message.full_name()).collect();
+
+        assert_eq!(
+            names,
+            vec![
+                "app.accounts.Outer",
+                "app.accounts.Outer.Inner",
+                "app.accounts.Outer.Inner.Deepest",
+                "app.accounts.Request",
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_own_fields_of_a_message() {
+        let body = rpc_body(
+            " {\n  string email = 1;\n  string password = 2 [(cali.sensitive) = true];\n  message Inner {\n    int32 skipped = 1;\n  }\n  oneof contact {\n    string phone = 3;\n  }\n  repeated Inner items = 4;\n}\n",
+        )
+        .unwrap();
+        let fields = parse_fields(&own_fields(body));
+        let summary: Vec<(&str, u32, bool, bool)> = fields
+            .iter()
+            .map(|field| {
+                (
+                    field.name.as_str(),
+                    field.number,
+                    field.repeated,
+                    field.sensitive,
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            summary,
+            vec![
+                ("email", 1, false, false),
+                ("password", 2, false, true),
+                ("phone", 3, false, false),
+                ("items", 4, true, false),
+            ]
+        );
+    }
+}
@@ -0,0 +1,327 @@
+use std::{collections::HashMap, fmt::Write};
+
+/// The message and rpc layout of your interface protos, used to render request and response
+/// payloads in logs. `setup_server!` builds it from the files in `interface/grpc`.
+#[derive(Debug, Clone, Default)]
+pub struct Schema {
+    messages: HashMap<String, Vec<FieldSchema>>,
+    rpcs: HashMap<String, (String, String)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FieldSchema {
+    pub number: u32,
+    pub name: String,
+    pub kind: String,
+    pub sensitive: bool,
+}
+
+impl FieldSchema {
+    pub fn new(number: u32, name: &str, kind: &str, sensitive: bool) -> Self {
+        Self {
+            number,
+            name: name.to_string(),
+            kind: kind.to_string(),
+            sensitive,
+        }
+    }
+}
+
+impl Schema {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `name` is the fully qualified message name, e.g. `package.Message`.
+    pub fn message(mut self, name: &str, fields: Vec<FieldSchema>) -> Self {
+        self.messages.insert(name.to_string(), fields);
+
+        self
+    }
+
+    /// `method` is written as `package.Service/Method`, with the fully qualified names of its
+    /// request and response messages.
+    pub fn rpc(mut self, method: &str, request: &str, response: &str) -> Self {
+        self.rpcs.insert(
+            method.to_string(),
+            (request.to_string(), response.to_string()),
+        );
+
+        self
+    }
+
+    /// The request and response message names of a method.
+    pub fn rpc_messages(&self, method: &str) -> Option<(&str, &str)> {
+        self.rpcs
+            .get(method)
+            .map(|(request, response)| (request.as_str(), response.as_str()))
+    }
+
+    /// Renders an encoded protobuf message, named by its fully qualified name, as
+    /// `{field: value, ...}`. Fields that are sensitive in the schema, or whose name is in `redact`
+    /// as `field`, `Message.field` or `package.Message.field`, are replaced with `<redacted>`.
+    /// Unknown fields are shown by number.
+    pub fn render(&self, message: &str, bytes: &[u8], redact: &[String]) -> String {
+        let mut out = String::new();
+        if self
+            .render_message(message, bytes, redact, &mut out)
+            .is_none()
+        {
+            return format!("<{} undecodable bytes>", bytes.len());
+        }
+
+        out
+    }
+
+    fn render_message(
+        &self,
+        message: &str,
+        mut bytes: &[u8],
+        redact: &[String],
+        out: &mut String,
+    ) -> Option<()> {
+        let fields = self.messages.get(message);
+        out.push('{');
+        let mut first = true;
+        while !bytes.is_empty() {
+            let key = read_varint(&mut bytes)?;
+            let number = (key >> 3) as u32;
+            let field =
+                fields.and_then(|fields| fields.iter().find(|field| field.number == number));
+
+            if !first {
+                out.push_str(", ");
+            }
+            first = false;
+            match field {
+                Some(field) => out.push_str(&field.name),
+                None => {
+                    let _ = write!(out, "#{}", number);
+                }
+            }
+            out.push_str(": ");
+
+            let value = read_value(key & 0x7, &mut bytes)?;
+            let redacted = field.is_some_and(|field| {
+                field.sensitive
+                    || redact.iter().any(|entry| {
+                        entry == &field.name
+                            || *entry == format!("{}.{}", short_name(message), field.name)
+                            || *entry == format!("{}.{}", message, field.name)
+                    })
+            });
+            if redacted {
+                out.push_str("<redacted>");
+                continue;
+            }
+
+            let kind = field.map(|field| field.kind.as_str()).unwrap_or_default();
+            match value {
+                WireValue::Varint(value) => match kind {
+                    "bool" => out.push_str(if value != 0 { "true" } else { "false" }),
+                    "sint32" | "sint64" => {
+                        let _ = write!(out, "{}", (value >> 1) as i64 ^ -((value & 1) as i64));
+                    }
+                    "int32" | "int64" => {
+                        let _ = write!(out, "{}", value as i64);
+                    }
+                    _ => {
+                        let _ = write!(out, "{}", value);
+                    }
+                },
+                WireValue::Fixed64(value) => match kind {
+                    "double" => {
+                        let _ = write!(out, "{}", f64::from_bits(value));
+                    }
+                    "sfixed64" => {
+                        let _ = write!(out, "{}", value as i64);
+                    }
+                    _ => {
+                        let _ = write!(out, "{}", value);
+                    }
+                },
+                WireValue::Fixed32(value) => match kind {
+                    "float" => {
+                        let _ = write!(out, "{}", f32::from_bits(value));
+                    }
+                    "sfixed32" => {
+                        let _ = write!(out, "{}", value as i32);
+                    }
+                    _ => {
+                        let _ = write!(out, "{}", value);
+                    }
+                },
+                WireValue::Bytes(value) => match kind {
+                    "string" => {
+                        let _ = write!(out, "{:?}", String::from_utf8_lossy(value));
+                    }
+                    kind => match self.resolve(message, kind) {
+                        Some(kind) => self.render_message(kind, value, redact, out)?,
+                        None => {
+                            let _ = write!(out, "<{} bytes>", value.len());
+                        }
+                    },
+                },
+            }
+        }
+        out.push('}');
+
+        Some(())
+    }
+
+    /// Finds the message a field's type refers to the way protoc does, in the message the field is
+    /// declared in and then each scope around it, so `Device` in `app.Login` is either
+    /// `app.Login.Device` or `app.Device`. Types starting with a `.` are fully qualified.
+    fn resolve<'a>(&'a self, message: &str, kind: &str) -> Option<&'a str> {
+        let found = |name: &str| {
+            self.messages
+                .get_key_value(name)
+                .map(|(name, _)| name.as_str())
+        };
+        if let Some(full_name) = kind.strip_prefix('.') {
+            return found(full_name);
+        }
+
+        let mut scope = Some(message);
+        while let Some(current) = scope {
+            if let Some(name) = found(&format!("{}.{}", current, kind)) {
+                return Some(name);
+            }
+            scope = current.rsplit_once('.').map(|(parent, _)| parent);
+        }
+        found(kind)
+    }
+}
+
+/// The message name without its package, e.g. `Account` for `models.Account`.
+fn short_name(name: &str) -> &str {
+    name.rsplit('.').next().unwrap_or(name)
+}
+
+enum WireValue<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Fixed32(u32),
+    Bytes(&'a [u8]),
+}
+
+fn read_value<'a>(wire_type: u64, bytes: &mut &'a [u8]) -> Option<WireValue<'a>> {
+    match wire_type {
+        0 => read_varint(bytes).map(WireValue::Varint),
+        1 => {
+            let (value, rest) = bytes.split_first_chunk::<8>()?;
+            *bytes = rest;
+            Some(WireValue::Fixed64(u64::from_le_bytes(*value)))
+        }
+        2 => {
+            let length = read_varint(bytes)? as usize;
+            if length > bytes.len() {
+                return None;
+            }
+            let (value, rest) = bytes.split_at(length);
+            *bytes = rest;
+            Some(WireValue::Bytes(value))
+        }
+        5 => {
+            let (value, rest) = bytes.split_first_chunk::<4>()?;
+            *bytes = rest;
+            Some(WireValue::Fixed32(u32::from_le_bytes(*value)))
+        }
+        _ => None,
+    }
+}
+
+fn read_varint(bytes: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (byte, rest) = bytes.split_first()?;
+        *bytes = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_nested_messages_with_redaction() {
+        let schema = Schema::new()
+            .message(
+                "app.Login",
+                vec![
+                    FieldSchema::new(1, "email", "string", false),
+                    FieldSchema::new(2, "password", "string", true),
+                    FieldSchema::new(3, "device", "models.Device", false),
+                ],
+            )
+            .message(
+                "models.Device",
+                vec![
+                    FieldSchema::new(1, "id", "int64", false),
+                    FieldSchema::new(2, "token", "string", false),
+                ],
+            );
+        let bytes = [
+            0x0a, 0x03, b'a', b'@', b'b', // email
+            0x12, 0x02, b'p', b'w', // password
+            0x1a, 0x05, 0x08, 0x07, 0x12, 0x01, b't', // device { id: 7, token: "t" }
+            0x20, 0x01, // unknown field 4
+        ];
+
+        assert_eq!(
+            schema.render("app.Login", &bytes, &["Device.token".to_string()]),
+            r#"{email: "a@b", password: <redacted>, device: {id: 7, token: <redacted>}, #4: 1}"#
+        );
+    }
+
+    #[test]
+    fn keeps_messages_of_the_same_name_in_different_packages_apart() {
+        let schema = Schema::new()
+            .message(
+                "accounts.Request",
+                vec![
+                    FieldSchema::new(1, "name", "string", false),
+                    FieldSchema::new(2, "card", "Card", false),
+                ],
+            )
+            .message(
+                "accounts.Request.Card",
+                vec![FieldSchema::new(1, "label", "string", false)],
+            )
+            .message(
+                "billing.Request",
+                vec![FieldSchema::new(1, "card", "string", true)],
+            )
+            .message(
+                "billing.Card",
+                vec![FieldSchema::new(1, "number", "string", true)],
+            )
+            .rpc(
+                "accounts.Accounts/Get",
+                "accounts.Request",
+                "accounts.Request",
+            )
+            .rpc(
+                "billing.Billing/Charge",
+                "billing.Request",
+                "billing.Request",
+            );
+        let bytes = [0x0a, 0x02, b'a', b'b'];
+        let card = [0x12, 0x04, 0x0a, 0x02, b'c', b'd'];
+
+        let (accounts, _) = schema.rpc_messages("accounts.Accounts/Get").unwrap();
+        let (billing, _) = schema.rpc_messages("billing.Billing/Charge").unwrap();
+        assert_eq!(schema.render(accounts, &bytes, &[]), r#"{name: "ab"}"#);
+        assert_eq!(schema.render(billing, &bytes, &[]), "{card: <redacted>}");
+        assert_eq!(
+            schema.render(accounts, &card, &[]),
+            r#"{card: {label: "cd"}}"#
+        );
+    }
+}
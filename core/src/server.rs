@@ -0,0 +1,428 @@
+use std::{
+    collections::HashMap, convert::Infallible, fmt, future::Future, net::SocketAddr, sync::Arc,
+};
+
+use bytes::Bytes;
+use http::{Request, Response};
+use tonic::{
+    body::BoxBody,
+    codegen::StdError,
+    server::NamedService,
+    service::{Routes, RoutesBuilder},
+    transport::{server::Router, Server},
+};
+use tower::{
+    layer::util::{Identity, Stack},
+    Layer, Service,
+};
+
+use crate::{
+    config::{server::ServerConf, CaliConfig},
+    context::{FromContext, GlobalContext},
+    helpers::split_host_and_port,
+    middleware::{
+        access_log::{AccessLogConf, AccessLogLayer},
+        api_key::ApiKeyLayer,
+        auth::{AuthConf, AuthLayer},
+        authorization::{AuthorizationLayer, Policy},
+        catch_panic::CatchPanicLayer,
+        deadline::{DeadlineLayer, TimeoutConf},
+        load_shed::LoadShedLayer,
+        rate_limit::{RateLimitConf, RateLimitLayer},
+        request_context::RequestContextLayer,
+        request_id::RequestIdLayer,
+        server_context::ServerContextLayer,
+        telemetry::TelemetryLayer,
+    },
+    protos::wire::Schema,
+    store::DatabaseConf,
+    ServerContext,
+};
+
+/// The tower stack cali wraps every service in, outermost layer last. This is the `Stack` your
+/// `CaliConfig::add_middleware` function receives.
+pub type CaliStack<T, C> = Stack<
+    AuthorizationLayer,
+    Stack<
+        RateLimitLayer,
+        Stack<
+            AuthLayer,
+            Stack<
+                ApiKeyLayer,
+                Stack<
+                    ServerContextLayer<T, ServerContext, C>,
+                    Stack<
+                        DeadlineLayer,
+                        Stack<
+                            CatchPanicLayer,
+                            Stack<
+                                TelemetryLayer,
+                                Stack<
+                                    LoadShedLayer,
+                                    Stack<
+                                        AccessLogLayer,
+                                        Stack<RequestIdLayer, Stack<RequestContextLayer, Identity>>,
+                                    >,
+                                >,
+                            >,
+                        >,
+                    >,
+                >,
+            >,
+        >,
+    >,
+>;
+
+/// Builds a controller from the context and adds its service to the routes.
+type ControllerSetup = Box<dyn FnOnce(&GlobalContext, &mut RoutesBuilder) -> Result<(), String>>;
+
+#[derive(Debug)]
+pub enum ServeError {
+    /// A config section couldn't be turned into its middleware.
+    Config(String),
+    /// A controller's dependencies couldn't be resolved from the context.
+    Context(String),
+    Database(sqlx::Error),
+    Transport(tonic::transport::Error),
+}
+
+impl fmt::Display for ServeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServeError::Config(message) => write!(f, "Invalid config: {}", message),
+            ServeError::Context(message) => write!(f, "Could not build controller: {}", message),
+            ServeError::Database(err) => write!(f, "Could not connect to the database: {}", err),
+            ServeError::Transport(err) => write!(f, "Server error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ServeError {}
+
+impl From<sqlx::Error> for ServeError {
+    fn from(err: sqlx::Error) -> Self {
+        ServeError::Database(err)
+    }
+}
+
+impl From<tonic::transport::Error> for ServeError {
+    fn from(err: tonic::transport::Error) -> Self {
+        ServeError::Transport(err)
+    }
+}
+
+/// Assembles the tonic server the way `setup_server!` does, from your config sections, your
+/// `CaliConfig` and your services. Use it directly when you want to own your main, or call
+/// `into_router` to drive the server yourself, e.g. with `serve_with_incoming` in tests.
+///
+/// ```ignore
+/// CaliServer::new(config.clone(), CaliConfig::new().enable_database())
+///     .bind_address(&config.bind_address)
+///     .database(&config.database)
+///     .auth(config.auth.as_ref())
+///     .add_service(GreeterServer::new(GreeterController::new()))
+///     .serve()
+///     .await?;
+/// ```
+pub struct CaliServer<T: 'static + Send + Sync, C: 'static + Send + Sync, R = CaliStack<T, C>> {
+    config: Arc<C>,
+    cali_config: CaliConfig<T, CaliStack<T, C>, R>,
+    bind_address: Option<String>,
+    database: Option<DatabaseConf>,
+    databases: HashMap<String, DatabaseConf>,
+    server: ServerConf,
+    auth: Option<AuthConf>,
+    anonymous_methods: Vec<String>,
+    policies: Vec<Policy>,
+    rate_limit: Option<RateLimitConf>,
+    timeouts: Option<TimeoutConf>,
+    access_log: Option<AccessLogConf>,
+    schema: Schema,
+    routes: RoutesBuilder,
+    controllers: Vec<ControllerSetup>,
+}
+
+impl<T: 'static + Send + Sync, C: 'static + Send + Sync, R> CaliServer<T, C, R> {
+    /// `config` is made available to controllers through `get_context`. `R` is the stack your
+    /// `CaliConfig::add_middleware` function returns, the cali stack when it isn't called.
+    pub fn new(config: Arc<C>, cali_config: CaliConfig<T, CaliStack<T, C>, R>) -> Self {
+        Self {
+            config,
+            cali_config,
+            bind_address: None,
+            database: None,
+            databases: HashMap::new(),
+            server: ServerConf::default(),
+            auth: None,
+            anonymous_methods: Vec::new(),
+            policies: Vec::new(),
+            rate_limit: None,
+            timeouts: None,
+            access_log: None,
+            schema: Schema::new(),
+            routes: RoutesBuilder::default(),
+            controllers: Vec::new(),
+        }
+    }
+
+    /// The `host:port` to listen on.
+    pub fn bind_address(mut self, bind_address: &str) -> Self {
+        self.bind_address = Some(bind_address.to_string());
+
+        self
+    }
+
+    /// Only connected to when the database is enabled on the `CaliConfig`.
+    pub fn database(mut self, conf: &DatabaseConf) -> Self {
+        self.database = Some(conf.clone());
+
+        self
+    }
+
+    /// Named databases, reached with `store::get_conn_named`. Connected along with the main one.
+    pub fn databases(mut self, databases: &HashMap<String, DatabaseConf>) -> Self {
+        self.databases = databases.clone();
+
+        self
+    }
+
+    pub fn server(mut self, conf: &ServerConf) -> Self {
+        self.server = conf.clone();
+
+        self
+    }
+
+    pub fn auth(mut self, conf: Option<&AuthConf>) -> Self {
+        self.auth = conf.cloned();
+
+        self
+    }
+
+    /// Methods, written as `package.Service/Method`, that can be called without credentials.
+    pub fn allow_anonymous(mut self, methods: &[&str]) -> Self {
+        self.anonymous_methods
+            .extend(methods.iter().map(|method| method.to_string()));
+
+        self
+    }
+
+    pub fn policies(mut self, policies: Vec<Policy>) -> Self {
+        self.policies.extend(policies);
+
+        self
+    }
+
+    pub fn rate_limit(mut self, conf: Option<&RateLimitConf>) -> Self {
+        self.rate_limit = conf.cloned();
+
+        self
+    }
+
+    pub fn timeouts(mut self, conf: Option<&TimeoutConf>) -> Self {
+        self.timeouts = conf.cloned();
+
+        self
+    }
+
+    /// `schema` is used to render request and response bodies at debug level.
+    pub fn access_log(mut self, conf: Option<&AccessLogConf>, schema: Schema) -> Self {
+        self.access_log = conf.cloned();
+        self.schema = schema;
+
+        self
+    }
+
+    pub fn add_service<S>(mut self, service: S) -> Self
+    where
+        S: Service<Request<BoxBody>, Response = Response<BoxBody>, Error = Infallible>
+            + NamedService
+            + Clone
+            + Send
+            + 'static,
+        S::Future: Send + 'static,
+    {
+        self.routes.add_service(service);
+
+        self
+    }
+
+    /// Adds a service whose controller is built with `FromContext` once the database is connected,
+    /// `make_service` wraps the controller in its generated server.
+    pub fn add_controller<Ctrl, S>(mut self, make_service: impl FnOnce(Ctrl) -> S + 'static) -> Self
+    where
+        Ctrl: FromContext + 'static,
+        S: Service<Request<BoxBody>, Response = Response<BoxBody>, Error = Infallible>
+            + NamedService
+            + Clone
+            + Send
+            + 'static,
+        S::Future: Send + 'static,
+    {
+        self.controllers.push(Box::new(move |context, routes| {
+            let controller = Ctrl::from_context(context)
+                .map_err(|err| format!("{}: {}", std::any::type_name::<Ctrl>(), err))?;
+            routes.add_service(make_service(controller));
+            Ok(())
+        }));
+
+        self
+    }
+
+    /// Connects to the database when enabled, and builds the middleware stack around the
+    /// services. The returned router is ready to be served.
+    pub async fn into_router(mut self) -> Result<Router<R>, ServeError>
+    where
+        R: Clone,
+    {
+        let server_context = if self.cali_config.database {
+            self.connect_databases().await?
+        } else {
+            ServerContext::default()
+        };
+
+        let api_key_conf = self.auth.as_ref().and_then(|auth| auth.api_keys.as_ref());
+        if api_key_conf.is_some() && server_context.db_pool.is_none() {
+            return Err(ServeError::Config(
+                "API key authentication needs the database to be enabled".to_string(),
+            ));
+        }
+        let api_key_layer = ApiKeyLayer::new(api_key_conf).map_err(ServeError::Config)?;
+        let anonymous_methods: Vec<&str> =
+            self.anonymous_methods.iter().map(String::as_str).collect();
+        let auth_layer = AuthLayer::new(self.auth.as_ref())
+            .map_err(ServeError::Config)?
+            .allow_anonymous(&anonymous_methods);
+        let rate_limit_layer =
+            RateLimitLayer::new(self.rate_limit.as_ref()).map_err(ServeError::Config)?;
+
+        let context_layer = ServerContextLayer {
+            config: self.config,
+            extendable_context: self.cali_config.global_context,
+            provided: self.cali_config.provided,
+            internal_context: Arc::new(server_context),
+        };
+        let global_context = GlobalContext::new(Arc::new(context_layer.context()));
+        for setup in self.controllers {
+            setup(&global_context, &mut self.routes).map_err(ServeError::Context)?;
+        }
+        let load_shed_layer = LoadShedLayer::new(
+            self.server
+                .concurrency_limit_per_connection
+                .filter(|_| self.server.load_shed),
+        );
+
+        let server_builder = self
+            .server
+            .apply(Server::builder())
+            .layer(RequestContextLayer)
+            .layer(RequestIdLayer)
+            .layer(AccessLogLayer::new(self.access_log.as_ref(), self.schema))
+            .layer(load_shed_layer)
+            .layer(TelemetryLayer)
+            .layer(CatchPanicLayer::new(self.cali_config.catch_panics))
+            .layer(DeadlineLayer::new(self.timeouts.as_ref()))
+            .layer(context_layer)
+            .layer(api_key_layer)
+            .layer(auth_layer)
+            .layer(rate_limit_layer)
+            .layer(AuthorizationLayer::new(self.policies));
+        let mut server_builder = (self.cali_config.middleware_setup)(server_builder);
+
+        Ok(server_builder.add_routes(self.routes.routes()))
+    }
+
+    /// Serves until the process is stopped.
+    pub async fn serve<ResBody>(self) -> Result<(), ServeError>
+    where
+        R: Layer<Routes> + Clone,
+        R::Service:
+            Service<Request<BoxBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+        <R::Service as Service<Request<BoxBody>>>::Future: Send + 'static,
+        <R::Service as Service<Request<BoxBody>>>::Error: Into<StdError> + Send,
+        ResBody: http_body::Body<Data = Bytes> + Send + 'static,
+        ResBody::Error: Into<StdError>,
+    {
+        self.serve_with_shutdown(std::future::pending()).await
+    }
+
+    /// Serves until `signal` completes, then lets in flight calls finish.
+    pub async fn serve_with_shutdown<ResBody>(
+        self,
+        signal: impl Future<Output = ()>,
+    ) -> Result<(), ServeError>
+    where
+        R: Layer<Routes> + Clone,
+        R::Service:
+            Service<Request<BoxBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+        <R::Service as Service<Request<BoxBody>>>::Future: Send + 'static,
+        <R::Service as Service<Request<BoxBody>>>::Error: Into<StdError> + Send,
+        ResBody: http_body::Body<Data = Bytes> + Send + 'static,
+        ResBody::Error: Into<StdError>,
+    {
+        let addr = self.addr()?;
+        let router = self.into_router().await?;
+
+        tracing::info!("GRPC server started, waiting for requests...");
+        router.serve_with_shutdown(addr, signal).await?;
+
+        Ok(())
+    }
+
+    async fn connect_databases(&self) -> Result<ServerContext, ServeError> {
+        let Some(database) = &self.database else {
+            return Err(ServeError::Config(
+                "The database is enabled but no database config was given".to_string(),
+            ));
+        };
+
+        tracing::info!("Connecting to DB...");
+        let mut server_context = ServerContext {
+            db_pool: Some(database.connect().await?),
+            read_pools: database.connect_replicas().await?,
+            ..Default::default()
+        };
+        for (name, database) in self.databases.iter() {
+            if !database.replicas.is_empty() {
+                return Err(ServeError::Config(format!(
+                    "Read replicas are only supported on the main database, not on {}",
+                    name
+                )));
+            }
+            server_context
+                .named_pools
+                .insert(name.clone(), database.connect().await?);
+        }
+        tracing::info!("Connected!");
+
+        Ok(server_context)
+    }
+
+    fn addr(&self) -> Result<SocketAddr, ServeError> {
+        let bind_address = self
+            .bind_address
+            .as_deref()
+            .ok_or_else(|| ServeError::Config("No bind address set".to_string()))?;
+        let (host, port) = split_host_and_port(bind_address);
+
+        format!("{}:{}", host, port)
+            .parse()
+            .map_err(|_| ServeError::Config(format!("Invalid bind address {}", bind_address)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn serves_with_added_middleware() {
+        let cali_config = CaliConfig::<(), CaliStack<(), ()>, _>::new()
+            .add_middleware(|server| server.layer(tonic::service::interceptor(Ok)));
+
+        CaliServer::new(Arc::new(()), cali_config)
+            .bind_address("127.0.0.1:0")
+            .serve_with_shutdown(std::future::ready(()))
+            .await
+            .unwrap();
+    }
+}
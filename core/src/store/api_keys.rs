@@ -0,0 +1,165 @@
+use sha2::{Digest, Sha256};
+use sqlx::{
+    types::chrono::{DateTime, Utc},
+    FromRow,
+};
+
+use super::{rebind, snare::DBConnection, Backend, DbConn};
+
+/// Version prefix of the migration files `cali new` and `cali generate api-keys` write.
+pub const MIGRATION_VERSION: &str = "20241001000000";
+
+/// The migration creating the `cali_api_keys` table on `backend`.
+pub fn up_migration(backend: Backend) -> &'static str {
+    match backend {
+        Backend::MySql => {
+            "CREATE TABLE cali_api_keys (
+    id BIGINT NOT NULL AUTO_INCREMENT PRIMARY KEY,
+    name VARCHAR(255) NOT NULL,
+    prefix VARCHAR(16) NOT NULL,
+    key_hash CHAR(64) NOT NULL UNIQUE,
+    subject VARCHAR(255) NOT NULL,
+    roles VARCHAR(1024) NOT NULL DEFAULT '',
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    revoked_at TIMESTAMP NULL
+);
+"
+        }
+        Backend::Postgres => {
+            "CREATE TABLE cali_api_keys (
+    id BIGSERIAL PRIMARY KEY,
+    name VARCHAR(255) NOT NULL,
+    prefix VARCHAR(16) NOT NULL,
+    key_hash VARCHAR(64) NOT NULL UNIQUE,
+    subject VARCHAR(255) NOT NULL,
+    roles VARCHAR(1024) NOT NULL DEFAULT '',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    revoked_at TIMESTAMPTZ NULL
+);
+"
+        }
+        Backend::Sqlite => {
+            "CREATE TABLE cali_api_keys (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL,
+    prefix TEXT NOT NULL,
+    key_hash TEXT NOT NULL UNIQUE,
+    subject TEXT NOT NULL,
+    roles TEXT NOT NULL DEFAULT '',
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    revoked_at TIMESTAMP NULL
+);
+"
+        }
+    }
+}
+
+pub const DOWN_MIGRATION: &str = "DROP TABLE cali_api_keys;
+";
+
+/// A row of the cali managed `cali_api_keys` table. Only a hash of the key is stored, the key
+/// itself is shown once when it is created.
+#[derive(Debug, Clone, FromRow)]
+pub struct ApiKey {
+    pub id: i64,
+    pub name: String,
+    pub prefix: String,
+    pub subject: String,
+    /// Space separated roles granted to callers using this key.
+    pub roles: String,
+    pub created_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl ApiKey {
+    pub fn role_list(&self) -> Vec<String> {
+        self.roles
+            .split_whitespace()
+            .map(|role| role.to_string())
+            .collect()
+    }
+}
+
+pub fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+fn generate_key() -> String {
+    format!(
+        "ck_{}{}",
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
+    )
+}
+
+/// Creates a new key for `subject` and returns it along with the plain text key.
+pub async fn create(
+    conn: &mut DbConn,
+    name: &str,
+    subject: &str,
+    roles: &[String],
+) -> Result<(ApiKey, String), sqlx::Error> {
+    let key = generate_key();
+    let insert =
+        "INSERT INTO cali_api_keys (name, prefix, key_hash, subject, roles) VALUES (?, ?, ?, ?, ?)";
+
+    // MySQL has no RETURNING, so the row is read back by its id
+    #[cfg(feature = "mysql")]
+    let api_key = {
+        let result = sqlx::query(insert)
+            .bind(name)
+            .bind(&key[..11])
+            .bind(hash_key(&key))
+            .bind(subject)
+            .bind(roles.join(" "))
+            .execute(&mut *conn)
+            .await?;
+
+        sqlx::query_as::<_, ApiKey>("SELECT * FROM cali_api_keys WHERE id = ?")
+            .bind(result.last_insert_id() as i64)
+            .fetch_one(conn)
+            .await?
+    };
+    #[cfg(not(feature = "mysql"))]
+    let api_key = sqlx::query_as::<_, ApiKey>(&format!("{} RETURNING *", rebind(insert)))
+        .bind(name)
+        .bind(&key[..11])
+        .bind(hash_key(&key))
+        .bind(subject)
+        .bind(roles.join(" "))
+        .fetch_one(conn)
+        .await?;
+
+    Ok((api_key, key))
+}
+
+pub async fn list<'c, C: DBConnection<'c>>(conn: C) -> Result<Vec<ApiKey>, sqlx::Error> {
+    sqlx::query_as::<_, ApiKey>("SELECT * FROM cali_api_keys ORDER BY id")
+        .fetch_all(conn)
+        .await
+}
+
+/// Revokes the key, returns false when there is no active key with this id.
+pub async fn revoke<'c, C: DBConnection<'c>>(conn: C, id: i64) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(&rebind(
+        "UPDATE cali_api_keys SET revoked_at = CURRENT_TIMESTAMP WHERE id = ? AND revoked_at IS NULL",
+    ))
+    .bind(id)
+    .execute(conn)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Looks up an active key by its plain text value.
+pub async fn find_active<'c, C: DBConnection<'c>>(
+    conn: C,
+    key: &str,
+) -> Result<Option<ApiKey>, sqlx::Error> {
+    sqlx::query_as::<_, ApiKey>(&rebind(
+        "SELECT * FROM cali_api_keys WHERE key_hash = ? AND revoked_at IS NULL",
+    ))
+    .bind(hash_key(key))
+    .fetch_optional(conn)
+    .await
+}
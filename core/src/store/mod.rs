@@ -1,15 +1,569 @@
-use sqlx::{pool::PoolConnection, MySql};
+use std::{
+    borrow::Cow,
+    fmt,
+    str::FromStr,
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::{Duration, Instant},
+};
 
-use crate::{helpers::get_context, ServerContext};
+use serde::{Deserialize, Serialize};
+use sqlx::{migrate::MigrateDatabase, pool::PoolConnection, Connection, Executor};
+use tonic::Status;
 
+use crate::{
+    helpers::{get_remaining_time, try_get_context, ContextError},
+    ServerContext,
+};
+
+pub mod api_keys;
 pub mod snare;
 
-pub async fn get_conn<T: From<sqlx::Error>>() -> Result<PoolConnection<MySql>, T> {
-    let svr_ctx = get_context(|core_ctx: &ServerContext| core_ctx.clone());
-    let conn = svr_ctx
-        .db_pool
-        .expect("Database isn't enabled in cali config, why are you asking me for a connection?")
-        .acquire()
-        .await?;
+/// The sqlx database of the backend cali_core was built for.
+#[cfg(feature = "mysql")]
+pub type Db = sqlx::MySql;
+#[cfg(feature = "postgres")]
+pub type Db = sqlx::Postgres;
+#[cfg(feature = "sqlite")]
+pub type Db = sqlx::Sqlite;
+
+pub type DbPool = sqlx::Pool<Db>;
+pub type DbPoolOptions = sqlx::pool::PoolOptions<Db>;
+pub type DbConn = <Db as sqlx::Database>::Connection;
+pub type DbConnectOptions = <DbConn as Connection>::Options;
+pub type DbQueryResult = <Db as sqlx::Database>::QueryResult;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    MySql,
+    Postgres,
+    Sqlite,
+}
+
+impl Backend {
+    /// The backend selected with cali_core's cargo features.
+    #[cfg(feature = "mysql")]
+    pub const CURRENT: Backend = Backend::MySql;
+    #[cfg(feature = "postgres")]
+    pub const CURRENT: Backend = Backend::Postgres;
+    #[cfg(feature = "sqlite")]
+    pub const CURRENT: Backend = Backend::Sqlite;
+
+    /// Guesses the backend from a connection url's scheme.
+    pub fn from_url(url: &str) -> Option<Backend> {
+        match url.split_once(':')?.0 {
+            "mysql" | "mariadb" => Some(Backend::MySql),
+            "postgres" | "postgresql" => Some(Backend::Postgres),
+            "sqlite" => Some(Backend::Sqlite),
+            _ => None,
+        }
+    }
+
+    /// The cargo feature that selects this backend, in cali_core as well as sqlx.
+    pub fn feature(&self) -> &'static str {
+        match self {
+            Backend::MySql => "mysql",
+            Backend::Postgres => "postgres",
+            Backend::Sqlite => "sqlite",
+        }
+    }
+
+    /// The most bind parameters a single statement can have.
+    pub fn max_bind_parameters(&self) -> usize {
+        match self {
+            Backend::MySql | Backend::Postgres => 65_535,
+            Backend::Sqlite => 32_766,
+        }
+    }
+
+    /// The OpenTelemetry `db.system` name.
+    pub fn system(&self) -> &'static str {
+        match self {
+            Backend::MySql => "mysql",
+            Backend::Postgres => "postgresql",
+            Backend::Sqlite => "sqlite",
+        }
+    }
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "mysql" => Ok(Backend::MySql),
+            "postgres" => Ok(Backend::Postgres),
+            "sqlite" => Ok(Backend::Sqlite),
+            _ => Err(format!(
+                "Unknown database {}, expected mysql, postgres or sqlite",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.feature())
+    }
+}
+
+/// The bind parameter at `index`, counting from 1, e.g. `?` for MySQL and `$1` for Postgres.
+pub fn placeholder(index: usize) -> String {
+    match Backend::CURRENT {
+        Backend::Postgres => format!("${}", index),
+        Backend::MySql | Backend::Sqlite => "?".to_string(),
+    }
+}
+
+/// Rewrites the `?` bind parameters of a query into the current backend's placeholders. The
+/// query can't contain a literal `?`.
+pub fn rebind(query: &str) -> Cow<'_, str> {
+    if Backend::CURRENT != Backend::Postgres {
+        return Cow::Borrowed(query);
+    }
+
+    let mut rebound = String::with_capacity(query.len() + 8);
+    for (index, part) in query.split('?').enumerate() {
+        if index > 0 {
+            rebound.push_str(&placeholder(index));
+        }
+        rebound.push_str(part);
+    }
+
+    Cow::Owned(rebound)
+}
+
+/// How many bytes `value` adds to a statement as a bind parameter, used to keep batches under
+/// MySQL's `max_allowed_packet`. The other backends have no such limit, so it's only measured on
+/// MySQL and 0 elsewhere.
+pub fn encoded_len<'q, T: sqlx::Encode<'q, Db>>(value: &T) -> usize {
+    #[cfg(feature = "mysql")]
+    {
+        let mut buffer = Vec::new();
+        let _ = value.encode_by_ref(&mut buffer);
+        buffer.len()
+    }
+    #[cfg(not(feature = "mysql"))]
+    {
+        let _ = value;
+        0
+    }
+}
+
+/// The id the database generated for the row `result` inserted, `None` on Postgres, which only
+/// returns it through `RETURNING`.
+pub fn last_insert_id(result: &DbQueryResult) -> Option<u64> {
+    #[cfg(feature = "mysql")]
+    {
+        Some(result.last_insert_id())
+    }
+    #[cfg(feature = "sqlite")]
+    {
+        Some(result.last_insert_rowid() as u64)
+    }
+    #[cfg(feature = "postgres")]
+    {
+        let _ = result;
+        None
+    }
+}
+
+/// Drops the database at `url` if it exists and creates it again, used to start tests from a
+/// clean slate.
+pub async fn recreate_database(url: &str) -> Result<(), sqlx::Error> {
+    if Db::database_exists(url).await? {
+        Db::drop_database(url).await?;
+    }
+
+    Db::create_database(url).await
+}
+
+/// The pool of a read replica. `get_read_conn` skips it for a while after it failed to hand out a
+/// connection, so reads don't keep waiting on a replica that is down.
+#[derive(Debug, Clone)]
+pub struct ReadReplica {
+    pub pool: DbPool,
+    acquire_timeout: Duration,
+    retry_after: Duration,
+    down_until: Arc<Mutex<Option<Instant>>>,
+}
+
+impl ReadReplica {
+    fn is_down(&self) -> bool {
+        self.down_until
+            .lock()
+            .unwrap()
+            .is_some_and(|down_until| Instant::now() < down_until)
+    }
+
+    async fn acquire(&self) -> Result<PoolConnection<Db>, ConnError> {
+        let result = tokio::time::timeout(self.acquire_timeout, acquire(&self.pool))
+            .await
+            .unwrap_or(Err(ConnError::Sqlx(sqlx::Error::PoolTimedOut)));
+        if let Err(ConnError::Sqlx(_)) = &result {
+            *self.down_until.lock().unwrap() = Some(Instant::now() + self.retry_after);
+        }
+
+        result
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DatabaseConf {
+    pub url: String,
+    pub num_connections: u32,
+    /// Urls of read replicas, used by `get_read_conn`. Each gets a pool of `num-connections`.
+    #[serde(default)]
+    pub replicas: Vec<String>,
+    /// How long `get_read_conn` waits for a replica connection before falling back to the
+    /// primary, 1 second by default.
+    #[serde(default)]
+    pub replica_acquire_timeout_ms: Option<u64>,
+    /// How long a replica that didn't hand out a connection is skipped, 30 seconds by default.
+    #[serde(default)]
+    pub replica_retry_secs: Option<u64>,
+    /// Connections kept open even when they're idle.
+    #[serde(default)]
+    pub min_connections: u32,
+    /// How long `get_conn` waits for a free connection, sqlx defaults to 30 seconds.
+    #[serde(default)]
+    pub acquire_timeout_ms: Option<u64>,
+    /// Idle connections over `min-connections` are closed after this long, 10 minutes by default.
+    #[serde(default)]
+    pub idle_timeout_secs: Option<u64>,
+    /// Connections are replaced after this long, 30 minutes by default.
+    #[serde(default)]
+    pub max_lifetime_secs: Option<u64>,
+    /// Prepared statements cached per connection, 100 by default.
+    #[serde(default)]
+    pub statement_cache_size: Option<usize>,
+    /// Statements run on every new connection, e.g. `SET time_zone = '+00:00'`.
+    #[serde(default)]
+    pub init_sql: Vec<String>,
+    #[serde(default)]
+    pub connect_retry: ConnectRetryConf,
+}
+
+/// How long to keep trying to reach the database at startup, backing off exponentially between
+/// attempts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct ConnectRetryConf {
+    /// No attempts are started after this long, 0 fails on the first error.
+    pub deadline_secs: u64,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+impl Default for ConnectRetryConf {
+    fn default() -> Self {
+        Self {
+            deadline_secs: 60,
+            initial_backoff_ms: 250,
+            max_backoff_ms: 5000,
+        }
+    }
+}
+
+impl DatabaseConf {
+    pub async fn connect(&self) -> Result<DbPool, sqlx::Error> {
+        self.connect_to(&self.url).await
+    }
+
+    /// The replicas share the primary's pool settings.
+    pub async fn connect_replicas(&self) -> Result<Vec<ReadReplica>, sqlx::Error> {
+        let mut replicas = Vec::with_capacity(self.replicas.len());
+        for url in self.replicas.iter() {
+            replicas.push(ReadReplica {
+                pool: self.connect_to(url).await?,
+                acquire_timeout: Duration::from_millis(
+                    self.replica_acquire_timeout_ms.unwrap_or(1000),
+                ),
+                retry_after: Duration::from_secs(self.replica_retry_secs.unwrap_or(30)),
+                down_until: Arc::default(),
+            });
+        }
+
+        Ok(replicas)
+    }
+
+    async fn connect_to(&self, url: &str) -> Result<DbPool, sqlx::Error> {
+        let mut connect_options = DbConnectOptions::from_str(url)?;
+        if let Some(size) = self.statement_cache_size {
+            connect_options = connect_options.statement_cache_capacity(size);
+        }
+
+        let mut pool_options = DbPoolOptions::new()
+            .max_connections(self.num_connections)
+            .min_connections(self.min_connections)
+            .test_before_acquire(true);
+        if let Some(timeout) = self.acquire_timeout_ms {
+            pool_options = pool_options.acquire_timeout(Duration::from_millis(timeout));
+        }
+        if let Some(timeout) = self.idle_timeout_secs {
+            pool_options = pool_options.idle_timeout(Duration::from_secs(timeout));
+        }
+        if let Some(lifetime) = self.max_lifetime_secs {
+            pool_options = pool_options.max_lifetime(Duration::from_secs(lifetime));
+        }
+        if !self.init_sql.is_empty() {
+            let init_sql = Arc::new(self.init_sql.clone());
+            pool_options = pool_options.after_connect(move |conn, _| {
+                let init_sql = init_sql.clone();
+                Box::pin(async move {
+                    for statement in init_sql.iter() {
+                        conn.execute(statement.as_str()).await?;
+                    }
+                    Ok(())
+                })
+            });
+        }
+
+        let retry = &self.connect_retry;
+        let deadline = Instant::now() + Duration::from_secs(retry.deadline_secs);
+        let mut backoff = Duration::from_millis(retry.initial_backoff_ms);
+        loop {
+            // sqlx retries refused connections itself, keep those attempts within the deadline
+            let attempt = pool_options.clone().connect_with(connect_options.clone());
+            let result = match deadline.checked_duration_since(Instant::now()) {
+                Some(remaining) if !remaining.is_zero() => tokio::time::timeout(remaining, attempt)
+                    .await
+                    .unwrap_or(Err(sqlx::Error::PoolTimedOut)),
+                _ => attempt.await,
+            };
+            let err = match result {
+                Ok(pool) => return Ok(pool),
+                // A malformed url won't fix itself
+                Err(err @ sqlx::Error::Configuration(_)) => return Err(err),
+                Err(err) => err,
+            };
+            if Instant::now() + backoff > deadline {
+                return Err(err);
+            }
+
+            tracing::warn!(
+                "Could not connect to the database, retrying in {}ms: {}",
+                backoff.as_millis(),
+                err
+            );
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(Duration::from_millis(retry.max_backoff_ms));
+        }
+    }
+}
+
+/// Why a connection couldn't be handed out. Converts into a `Status` for controllers, and into
+/// a `sqlx::Error` for code that only deals in those.
+#[derive(Debug)]
+pub enum ConnError {
+    Context(ContextError),
+    /// The database isn't enabled in the `CaliConfig`.
+    Disabled,
+    /// There is no such database in the `databases` config section.
+    UnknownDatabase(String),
+    /// The request's deadline passed while waiting for a connection.
+    DeadlineExceeded,
+    Sqlx(sqlx::Error),
+}
+
+impl fmt::Display for ConnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnError::Context(err) => write!(f, "{}", err),
+            ConnError::Disabled => write!(f, "The database isn't enabled in the cali config"),
+            ConnError::UnknownDatabase(name) => {
+                write!(f, "No database named {} is configured", name)
+            }
+            ConnError::DeadlineExceeded => write!(f, "Deadline exceeded waiting for a connection"),
+            ConnError::Sqlx(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for ConnError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConnError::Context(err) => Some(err),
+            ConnError::Sqlx(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<ContextError> for ConnError {
+    fn from(err: ContextError) -> Self {
+        ConnError::Context(err)
+    }
+}
+
+impl From<sqlx::Error> for ConnError {
+    fn from(err: sqlx::Error) -> Self {
+        ConnError::Sqlx(err)
+    }
+}
+
+impl From<ConnError> for Status {
+    fn from(err: ConnError) -> Self {
+        match err {
+            ConnError::DeadlineExceeded => Status::deadline_exceeded("Deadline exceeded"),
+            ConnError::Sqlx(err) => {
+                tracing::error!("Could not get a database connection: {}", err);
+                Status::unavailable("Database unavailable")
+            }
+            err => {
+                tracing::error!("Could not get a database connection: {}", err);
+                Status::internal("Internal server error")
+            }
+        }
+    }
+}
+
+impl From<ConnError> for sqlx::Error {
+    fn from(err: ConnError) -> Self {
+        match err {
+            ConnError::Sqlx(err) => err,
+            ConnError::DeadlineExceeded => sqlx::Error::PoolTimedOut,
+            err => sqlx::Error::Configuration(Box::new(err)),
+        }
+    }
+}
+
+/// Acquires a connection from the pool. Gives up with `DeadlineExceeded` when the request's
+/// deadline passes first. Errors are converted through `sqlx::Error`, use `try_get_conn` to keep
+/// them apart.
+pub async fn get_conn<T: From<sqlx::Error>>() -> Result<PoolConnection<Db>, T> {
+    Ok(try_get_conn().await.map_err(sqlx::Error::from)?)
+}
+
+/// `get_conn`, returning a `ConnError` that `?` turns into the right `Status`.
+#[tracing::instrument(name = "cali.get_conn", skip_all)]
+pub async fn try_get_conn() -> Result<PoolConnection<Db>, ConnError> {
+    let pool = try_get_context(|core_ctx: &ServerContext| core_ctx.db_pool.clone())?
+        .ok_or(ConnError::Disabled)?;
+
+    acquire(&pool).await
+}
+
+/// Acquires a connection from a pool in the `databases` config section.
+pub async fn get_conn_named<T: From<sqlx::Error>>(name: &str) -> Result<PoolConnection<Db>, T> {
+    Ok(try_get_conn_named(name).await.map_err(sqlx::Error::from)?)
+}
+
+/// `get_conn_named`, returning a `ConnError`.
+#[tracing::instrument(name = "cali.get_conn", skip_all, fields(db.name = name))]
+pub async fn try_get_conn_named(name: &str) -> Result<PoolConnection<Db>, ConnError> {
+    let pool = try_get_context(|core_ctx: &ServerContext| core_ctx.named_pools.get(name).cloned())?
+        .ok_or_else(|| ConnError::UnknownDatabase(name.to_string()))?;
+
+    acquire(&pool).await
+}
+
+/// Acquires a connection from one of the main database's read replicas, taking turns between
+/// them. Falls back to the primary when there are no replicas, or none of them hand out a
+/// connection within `replica-acquire-timeout-ms`. A replica that failed is skipped for
+/// `replica-retry-secs`. Only use it for queries that can live with replication lag.
+pub async fn get_read_conn<T: From<sqlx::Error>>() -> Result<PoolConnection<Db>, T> {
+    Ok(try_get_read_conn().await.map_err(sqlx::Error::from)?)
+}
+
+/// `get_read_conn`, returning a `ConnError`.
+#[tracing::instrument(name = "cali.get_read_conn", skip_all)]
+pub async fn try_get_read_conn() -> Result<PoolConnection<Db>, ConnError> {
+    let (replicas, next) = try_get_context(|core_ctx: &ServerContext| {
+        (
+            core_ctx.read_pools.clone(),
+            core_ctx.next_read_pool.fetch_add(1, Ordering::Relaxed),
+        )
+    })?;
+
+    for offset in 0..replicas.len() {
+        let replica = &replicas[(next + offset) % replicas.len()];
+        if replica.is_down() {
+            continue;
+        }
+        match replica.acquire().await {
+            Ok(conn) => return Ok(conn),
+            // The primary won't do any better
+            Err(ConnError::DeadlineExceeded) => return Err(ConnError::DeadlineExceeded),
+            Err(err) => tracing::warn!("Read replica unavailable: {}", err),
+        }
+    }
+
+    try_get_conn().await
+}
+
+async fn acquire(pool: &DbPool) -> Result<PoolConnection<Db>, ConnError> {
+    let conn = match get_remaining_time() {
+        Some(remaining) => tokio::time::timeout(remaining, pool.acquire())
+            .await
+            .map_err(|_| ConnError::DeadlineExceeded)??,
+        None => pool.acquire().await?,
+    };
+
     Ok(conn)
 }
+
+/// Runs `test` inside a request, with an in-memory SQLite database as the server's pool. The pool
+/// has a single connection, every connection to `sqlite::memory:` opens a database of its own.
+#[cfg(all(test, feature = "sqlite"))]
+pub(crate) async fn with_memory_db<F: std::future::Future>(
+    test: impl FnOnce(DbPool) -> F,
+) -> F::Output {
+    let pool = DbPoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    let mut context: std::collections::HashMap<std::any::TypeId, crate::MapKey> =
+        std::collections::HashMap::new();
+    context.insert(
+        std::any::TypeId::of::<ServerContext>(),
+        Arc::new(ServerContext {
+            db_pool: Some(pool.clone()),
+            ..Default::default()
+        }),
+    );
+
+    crate::SERVER_CONTEXT
+        .scope(
+            Arc::new(context),
+            crate::REQUEST_CONTEXT.scope(Default::default(), test(pool)),
+        )
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rebinds_placeholders_for_the_backend() {
+        let query = "SELECT * FROM accounts WHERE id = ? AND name = ?";
+        let expected = match Backend::CURRENT {
+            Backend::Postgres => "SELECT * FROM accounts WHERE id = $1 AND name = $2",
+            Backend::MySql | Backend::Sqlite => query,
+        };
+
+        assert_eq!(rebind(query), expected);
+    }
+
+    #[tokio::test]
+    async fn skips_a_replica_that_is_down() {
+        let url = match Backend::CURRENT {
+            Backend::MySql => "mysql://127.0.0.1:1/cali",
+            Backend::Postgres => "postgres://127.0.0.1:1/cali",
+            Backend::Sqlite => "sqlite:///nonexistent/cali.db",
+        };
+        let replica = ReadReplica {
+            pool: DbPoolOptions::new().connect_lazy(url).unwrap(),
+            acquire_timeout: Duration::from_millis(100),
+            retry_after: Duration::from_secs(30),
+            down_until: Arc::default(),
+        };
+
+        assert!(!replica.is_down());
+        assert!(replica.acquire().await.is_err());
+        assert!(replica.is_down());
+    }
+}
@@ -1,7 +1,22 @@
-use sqlx::Executor;
+use std::marker::PhantomData;
 
-pub trait DBConnection<'c>: Executor<'c, Database = sqlx::MySql> {}
-impl<'c, T: Executor<'c, Database = sqlx::MySql>> DBConnection<'c> for T {}
+use sqlx::{Connection, Executor, FromRow};
+use tracing::Instrument;
+
+use super::{rebind, Backend, Db, DbConn, DbQueryResult};
+
+/// Binds fields marked `#[snare(json)]` as JSON.
+pub use sqlx::types::Json;
+
+pub trait DBConnection<'c>: Executor<'c, Database = Db> {}
+impl<'c, T: Executor<'c, Database = Db>> DBConnection<'c> for T {}
+
+/// A query built by Snare, borrowing the statement and values from the `Snare` it came from.
+pub type SnareQuery<'a> = sqlx::query::Query<'a, Db, <Db as sqlx::Database>::Arguments<'a>>;
+
+/// A select built by Snare, mapping every row to `T`.
+pub type SnareQueryAs<'a, T> =
+    sqlx::query::QueryAs<'a, Db, T, <Db as sqlx::Database>::Arguments<'a>>;
 
 pub struct Snare<T> {
     pub query: String,
@@ -9,47 +24,1082 @@ pub struct Snare<T> {
     pub data: T,
 }
 
+/// Builds the statements for a trapped value. These return the sqlx query without running it, so
+/// running it yourself emits no span. The `execute_insert`, `execute_update`, `execute_delete`
+/// and `execute_upsert` helpers on `Snare` run the same statements inside a `snare.*` span.
 pub trait Ensnared {
-    fn insert<'a>(
-        &'a mut self,
-    ) -> sqlx::query::Query<
-        '_,
-        sqlx::MySql,
-        <sqlx::MySql as sqlx::Database>::Arguments<'_>,
-    >;
+    type Data;
+
+    fn insert<'a>(&'a mut self) -> SnareQuery<'a>
+    where
+        Self::Data: Ensnarable;
+
+    /// Sets the row with the same primary key to the trapped values. Soft deleted rows are left
+    /// alone, `restore` them first.
+    fn update<'a>(&'a mut self) -> SnareQuery<'a>
+    where
+        Self::Data: Updatable;
+
+    /// Sets every row matching `condition` to the trapped values, e.g.
+    /// `update_where("tenant_id = ?").bind(tenant_id)`. Write `?` for the condition's own values,
+    /// they are bound after the trapped ones. Soft deleted rows are left alone.
+    fn update_where<'a>(&'a mut self, condition: &str) -> SnareQuery<'a>
+    where
+        Self::Data: Updatable;
+
+    /// Deletes the row with the same primary key. Rows of a `#[snare(soft_delete = "...")]`
+    /// table are only marked as deleted, by setting the column to the current time.
+    fn delete<'a>(&'a mut self) -> SnareQuery<'a>
+    where
+        Self::Data: Updatable;
+
+    /// Clears the soft delete column of the row with the same primary key.
+    fn restore<'a>(&'a mut self) -> SnareQuery<'a>
+    where
+        Self::Data: SoftDeletable;
+
+    /// Inserts the trapped values, or overwrites the upsert columns of the row they conflict
+    /// with. Uses `ON DUPLICATE KEY UPDATE` on MySQL, which ignores the conflict columns and
+    /// checks every unique key, and `ON CONFLICT` elsewhere.
+    fn upsert<'a>(&'a mut self) -> SnareQuery<'a>
+    where
+        Self::Data: Upsertable;
+}
+
+/// What the `Ensnare` derive knows about a struct's table, shared by the struct and its
+/// changeset.
+pub trait Table {
+    /// The column set when a row is soft deleted, see `#[snare(soft_delete = "...")]`.
+    const SOFT_DELETE_COLUMN: Option<&'static str> = None;
 }
 
-pub trait Ensnarable {
+pub trait Ensnarable: Table {
     fn insert_parts(&self) -> (String, String);
 
-    fn capture<'a>(
-        &'a self,
-        query: sqlx::query::Query<
-            'a,
-            sqlx::MySql,
-            <sqlx::MySql as sqlx::Database>::Arguments<'_>,
-        >,
-    ) -> sqlx::query::Query<
-        'a,
-        sqlx::MySql,
-        <sqlx::MySql as sqlx::Database>::Arguments<'_>,
-    >;
-}
-
-impl<T: Ensnarable> Ensnared for Snare<T> {
-    fn insert<'a>(
-        &'a mut self,
-    ) -> sqlx::query::Query<
-        '_,
-        sqlx::MySql,
-        <sqlx::MySql as sqlx::Database>::Arguments<'_>,
-    > {
-        let (values, bindings) = self.data.insert_parts();
-        self.query = format!(
+    fn capture<'a>(&'a self, query: SnareQuery<'a>) -> SnareQuery<'a>;
+
+    /// Roughly how many bytes the bound values take up, see `store::encoded_len`.
+    fn encoded_len(&self) -> usize {
+        0
+    }
+
+    /// Sets the `#[snare(auto_increment)]` field to the id the database generated for it.
+    fn set_auto_increment(&mut self, _id: u64) {}
+}
+
+/// Implemented by the `Ensnare` derive for structs with a `#[snare(primary_key)]`, and by their
+/// generated changesets.
+pub trait Updatable: Table {
+    /// The columns an update sets, in binding order.
+    fn assigned_columns(&self) -> Vec<&'static str>;
+
+    fn capture_assignments<'a>(&'a self, query: SnareQuery<'a>) -> SnareQuery<'a>;
+
+    /// The primary key columns, in binding order.
+    fn key_columns(&self) -> Vec<&'static str>;
+
+    fn capture_key<'a>(&'a self, query: SnareQuery<'a>) -> SnareQuery<'a>;
+}
+
+/// Implemented by the `Ensnare` derive for structs with a primary key and a
+/// `#[snare(soft_delete = "...")]`, and by their generated changesets.
+pub trait SoftDeletable: Updatable {
+    /// The column set when a row is soft deleted.
+    const COLUMN: &'static str;
+}
+
+/// Implemented by the `Ensnare` derive for structs with a primary key or a
+/// `#[snare(on_conflict = "...")]`.
+pub trait Upsertable: Ensnarable {
+    /// The columns identifying the existing row, the primary key unless `on_conflict` is set.
+    fn conflict_columns(&self) -> Vec<&'static str>;
+
+    /// The columns overwritten on an existing row, the fields marked `#[snare(upsert)]`, or every
+    /// column but the conflict columns and primary key when none are.
+    fn upsert_columns(&self) -> Vec<&'static str>;
+}
+
+/// The `ON DUPLICATE KEY UPDATE` or `ON CONFLICT` clause of an upsert.
+fn upsert_clause<T: Upsertable>(data: &T) -> String {
+    let columns = data.upsert_columns();
+    let conflict_columns = data.conflict_columns();
+
+    match Backend::CURRENT {
+        Backend::MySql => {
+            // Setting a column to itself keeps the row, without the errors `INSERT IGNORE` swallows
+            let assignments = if columns.is_empty() {
+                format!("{} = {}", conflict_columns[0], conflict_columns[0])
+            } else {
+                columns
+                    .iter()
+                    .map(|column| format!("{} = VALUES({})", column, column))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            };
+            format!("ON DUPLICATE KEY UPDATE {}", assignments)
+        }
+        Backend::Postgres | Backend::Sqlite => {
+            let target = conflict_columns.join(", ");
+            if columns.is_empty() {
+                return format!("ON CONFLICT ({}) DO NOTHING", target);
+            }
+            let assignments = columns
+                .iter()
+                .map(|column| format!("{} = excluded.{}", column, column))
+                .collect::<Vec<String>>()
+                .join(", ");
+            format!("ON CONFLICT ({}) DO UPDATE SET {}", target, assignments)
+        }
+    }
+}
+
+/// `INSERT INTO t (a, b) VALUES (?, ?)`, or a row of column defaults when nothing is inserted.
+fn insert_statement(table_name: &str, columns: &str, bindings: &str) -> String {
+    if !columns.is_empty() {
+        return format!(
             "INSERT INTO {} ({}) VALUES ({})",
-            self.table_name, values, bindings
+            table_name, columns, bindings
         );
+    }
+
+    match Backend::CURRENT {
+        Backend::MySql => format!("INSERT INTO {} () VALUES ()", table_name),
+        Backend::Postgres | Backend::Sqlite => format!("INSERT INTO {} DEFAULT VALUES", table_name),
+    }
+}
+
+/// `a = ?, b = ?`, or a no-op assignment when there is nothing to set so the statement stays valid.
+fn assignments<T: Updatable>(data: &T) -> String {
+    let columns = data.assigned_columns();
+    if columns.is_empty() {
+        let key = data.key_columns()[0];
+        return format!("{} = {}", key, key);
+    }
+
+    columns
+        .iter()
+        .map(|column| format!("{} = ?", column))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// `a = ? AND b = ?` over the primary key columns.
+fn key_condition<T: Updatable>(data: &T) -> String {
+    data.key_columns()
+        .iter()
+        .map(|column| format!("{} = ?", column))
+        .collect::<Vec<String>>()
+        .join(" AND ")
+}
+
+/// `condition`, limited to rows that aren't soft deleted.
+fn undeleted<T: Table>(condition: String) -> String {
+    match T::SOFT_DELETE_COLUMN {
+        Some(column) => format!("({}) AND {} IS NULL", condition, column),
+        None => condition,
+    }
+}
+
+impl<T> Ensnared for Snare<T> {
+    type Data = T;
+
+    fn insert<'a>(&'a mut self) -> SnareQuery<'a>
+    where
+        T: Ensnarable,
+    {
+        let (values, bindings) = self.data.insert_parts();
+        self.query = insert_statement(&self.table_name, &values, &bindings);
 
         self.data.capture(sqlx::query(&self.query))
     }
+
+    fn update<'a>(&'a mut self) -> SnareQuery<'a>
+    where
+        T: Updatable,
+    {
+        let query = format!(
+            "UPDATE {} SET {} WHERE {}",
+            self.table_name,
+            assignments(&self.data),
+            undeleted::<T>(key_condition(&self.data))
+        );
+        self.query = rebind(&query).into_owned();
+
+        let query = self.data.capture_assignments(sqlx::query(&self.query));
+        self.data.capture_key(query)
+    }
+
+    fn update_where<'a>(&'a mut self, condition: &str) -> SnareQuery<'a>
+    where
+        T: Updatable,
+    {
+        let query = format!(
+            "UPDATE {} SET {} WHERE {}",
+            self.table_name,
+            assignments(&self.data),
+            undeleted::<T>(condition.to_string())
+        );
+        self.query = rebind(&query).into_owned();
+
+        self.data.capture_assignments(sqlx::query(&self.query))
+    }
+
+    fn delete<'a>(&'a mut self) -> SnareQuery<'a>
+    where
+        T: Updatable,
+    {
+        let query = match T::SOFT_DELETE_COLUMN {
+            Some(column) => format!(
+                "UPDATE {} SET {} = CURRENT_TIMESTAMP WHERE {} AND {} IS NULL",
+                self.table_name,
+                column,
+                key_condition(&self.data),
+                column
+            ),
+            None => format!(
+                "DELETE FROM {} WHERE {}",
+                self.table_name,
+                key_condition(&self.data)
+            ),
+        };
+        self.query = rebind(&query).into_owned();
+
+        self.data.capture_key(sqlx::query(&self.query))
+    }
+
+    fn restore<'a>(&'a mut self) -> SnareQuery<'a>
+    where
+        T: SoftDeletable,
+    {
+        let query = format!(
+            "UPDATE {} SET {} = NULL WHERE {}",
+            self.table_name,
+            <T as SoftDeletable>::COLUMN,
+            key_condition(&self.data)
+        );
+        self.query = rebind(&query).into_owned();
+
+        self.data.capture_key(sqlx::query(&self.query))
+    }
+
+    fn upsert<'a>(&'a mut self) -> SnareQuery<'a>
+    where
+        T: Upsertable,
+    {
+        let (values, bindings) = self.data.insert_parts();
+        // With nothing inserted there's nothing of ours to conflict on, and SQLite doesn't take
+        // an upsert clause after `DEFAULT VALUES`
+        self.query = if values.is_empty() {
+            insert_statement(&self.table_name, &values, &bindings)
+        } else {
+            format!(
+                "{} {}",
+                insert_statement(&self.table_name, &values, &bindings),
+                upsert_clause(&self.data)
+            )
+        };
+
+        self.data.capture(sqlx::query(&self.query))
+    }
+}
+
+/// Selects rows of `T`'s table. Rows of a `#[snare(soft_delete = "...")]` table that were deleted
+/// are left out, unless `with_deleted` is called.
+pub struct Select<T> {
+    pub query: String,
+    pub table_name: String,
+    with_deleted: bool,
+    row: PhantomData<T>,
+}
+
+impl<T: Table> Select<T> {
+    pub fn new(table_name: &str) -> Self {
+        Self {
+            query: "".to_string(),
+            table_name: table_name.to_string(),
+            with_deleted: false,
+            row: PhantomData,
+        }
+    }
+
+    /// Includes soft deleted rows.
+    pub fn with_deleted(mut self) -> Self {
+        self.with_deleted = true;
+
+        self
+    }
+
+    fn deleted_filter(&self) -> Option<String> {
+        T::SOFT_DELETE_COLUMN
+            .filter(|_| !self.with_deleted)
+            .map(|column| format!("{} IS NULL", column))
+    }
+
+    pub fn all<'a>(&'a mut self) -> SnareQueryAs<'a, T>
+    where
+        T: for<'r> FromRow<'r, <Db as sqlx::Database>::Row>,
+    {
+        self.query = match self.deleted_filter() {
+            Some(filter) => format!("SELECT * FROM {} WHERE {}", self.table_name, filter),
+            None => format!("SELECT * FROM {}", self.table_name),
+        };
+
+        sqlx::query_as(&self.query)
+    }
+
+    /// Selects the rows matching `condition`, e.g. `filter("email = ?").bind(email)`.
+    pub fn filter<'a>(&'a mut self, condition: &str) -> SnareQueryAs<'a, T>
+    where
+        T: for<'r> FromRow<'r, <Db as sqlx::Database>::Row>,
+    {
+        let query = match self.deleted_filter() {
+            Some(filter) => format!(
+                "SELECT * FROM {} WHERE ({}) AND {}",
+                self.table_name, condition, filter
+            ),
+            None => format!("SELECT * FROM {} WHERE {}", self.table_name, condition),
+        };
+        self.query = rebind(&query).into_owned();
+
+        sqlx::query_as(&self.query)
+    }
+}
+
+impl<T: Ensnarable> Snare<T> {
+    /// Builds the insert statement and runs it on the given connection inside a `snare.insert`
+    /// span.
+    pub async fn execute_insert<'c, C: DBConnection<'c>>(
+        &mut self,
+        conn: C,
+    ) -> Result<DbQueryResult, sqlx::Error> {
+        let span = tracing::info_span!(
+            "snare.insert",
+            db.system = Backend::CURRENT.system(),
+            db.operation = "INSERT",
+            db.sql.table = %self.table_name,
+        );
+        self.insert().execute(conn).instrument(span).await
+    }
+}
+
+#[cfg(not(feature = "mysql"))]
+impl<T: Ensnarable> Snare<T> {
+    /// Inserts the row and reads it back with `RETURNING *`, with the `#[snare(auto_increment)]`
+    /// id and the defaults of skipped columns filled in, inside a `snare.insert` span. On MySQL it
+    /// also needs a `#[snare(primary_key)]`, to select the row again.
+    pub async fn insert_returning(self, conn: &mut DbConn) -> Result<T, sqlx::Error>
+    where
+        T: for<'r> FromRow<'r, <Db as sqlx::Database>::Row>,
+    {
+        let span = tracing::info_span!(
+            "snare.insert",
+            db.system = Backend::CURRENT.system(),
+            db.operation = "INSERT",
+            db.sql.table = %self.table_name,
+        );
+
+        async move {
+            let (values, bindings) = self.data.insert_parts();
+            let query = format!(
+                "{} RETURNING *",
+                insert_statement(&self.table_name, &values, &bindings)
+            );
+            let row = self
+                .data
+                .capture(sqlx::query(&query))
+                .fetch_one(&mut *conn)
+                .await?;
+            T::from_row(&row)
+        }
+        .instrument(span)
+        .await
+    }
+}
+
+#[cfg(feature = "mysql")]
+impl<T: Ensnarable + Updatable> Snare<T> {
+    /// Inserts the row and reads it back, with the `#[snare(auto_increment)]` id and the defaults
+    /// of skipped columns filled in, inside a `snare.insert` span. MySQL has no `RETURNING`, so
+    /// the id is set from `last_insert_id` and the row selected again by its primary key, which
+    /// is why this needs one here but not on Postgres or SQLite.
+    pub async fn insert_returning(mut self, conn: &mut DbConn) -> Result<T, sqlx::Error>
+    where
+        T: for<'r> FromRow<'r, <Db as sqlx::Database>::Row>,
+    {
+        let span = tracing::info_span!(
+            "snare.insert",
+            db.system = Backend::CURRENT.system(),
+            db.operation = "INSERT",
+            db.sql.table = %self.table_name,
+        );
+
+        async move {
+            let result = self.insert().execute(&mut *conn).await?;
+            if let Some(id) = super::last_insert_id(&result) {
+                self.data.set_auto_increment(id);
+            }
+            let query = format!(
+                "SELECT * FROM {} WHERE {}",
+                self.table_name,
+                key_condition(&self.data)
+            );
+            self.query = rebind(&query).into_owned();
+            let row = self
+                .data
+                .capture_key(sqlx::query(&self.query))
+                .fetch_one(&mut *conn)
+                .await?;
+            T::from_row(&row)
+        }
+        .instrument(span)
+        .await
+    }
+}
+
+impl<T: Updatable> Snare<T> {
+    /// Builds the update statement and runs it on the given connection inside a `snare.update`
+    /// span.
+    pub async fn execute_update<'c, C: DBConnection<'c>>(
+        &mut self,
+        conn: C,
+    ) -> Result<DbQueryResult, sqlx::Error> {
+        let span = tracing::info_span!(
+            "snare.update",
+            db.system = Backend::CURRENT.system(),
+            db.operation = "UPDATE",
+            db.sql.table = %self.table_name,
+        );
+        self.update().execute(conn).instrument(span).await
+    }
+
+    /// Builds the delete statement and runs it on the given connection inside a `snare.delete`
+    /// span.
+    pub async fn execute_delete<'c, C: DBConnection<'c>>(
+        &mut self,
+        conn: C,
+    ) -> Result<DbQueryResult, sqlx::Error> {
+        let span = tracing::info_span!(
+            "snare.delete",
+            db.system = Backend::CURRENT.system(),
+            db.operation = if T::SOFT_DELETE_COLUMN.is_some() { "UPDATE" } else { "DELETE" },
+            db.sql.table = %self.table_name,
+        );
+        self.delete().execute(conn).instrument(span).await
+    }
+}
+
+impl<T: Upsertable> Snare<T> {
+    /// Builds the upsert statement and runs it on the given connection inside a `snare.upsert`
+    /// span.
+    pub async fn execute_upsert<'c, C: DBConnection<'c>>(
+        &mut self,
+        conn: C,
+    ) -> Result<DbQueryResult, sqlx::Error> {
+        let span = tracing::info_span!(
+            "snare.upsert",
+            db.system = Backend::CURRENT.system(),
+            db.operation = "INSERT",
+            db.sql.table = %self.table_name,
+        );
+        self.upsert().execute(conn).instrument(span).await
+    }
+}
+
+impl<T: Ensnarable> Snare<T> {
+    /// Inserts all of `rows` with multi-row inserts, see `InsertMany`. The `Ensnare` derive adds
+    /// an `insert_many` to the struct that takes its `#[snare(table = "...")]` instead.
+    pub fn insert_many(rows: Vec<T>, table_name: &str) -> InsertMany<T> {
+        InsertMany {
+            rows,
+            table_name: table_name.to_string(),
+            max_rows: usize::MAX,
+            max_bytes: 4 * 1024 * 1024,
+            in_transaction: false,
+        }
+    }
+}
+
+/// Inserts rows with as few `INSERT INTO t (...) VALUES (...), (...)` statements as possible.
+/// A statement takes rows until it reaches the backend's bind parameter limit, `max_rows` or
+/// `max_bytes` of bound values.
+pub struct InsertMany<T> {
+    rows: Vec<T>,
+    table_name: String,
+    max_rows: usize,
+    max_bytes: usize,
+    in_transaction: bool,
+}
+
+impl<T: Ensnarable> InsertMany<T> {
+    /// Caps the rows per statement.
+    pub fn max_rows(mut self, max_rows: usize) -> Self {
+        self.max_rows = max_rows.max(1);
+
+        self
+    }
+
+    /// Caps the bound bytes per statement, only measured on MySQL. Defaults to 4MiB, the
+    /// smallest `max_allowed_packet` MySQL ships with, raise it to match your server.
+    pub fn max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+
+        self
+    }
+
+    /// Runs every statement in one transaction, so either all rows are inserted or none are.
+    pub fn in_transaction(mut self) -> Self {
+        self.in_transaction = true;
+
+        self
+    }
+
+    /// Inserts the rows inside a `snare.insert_many` span and returns how many were inserted.
+    pub async fn execute(&self, conn: &mut DbConn) -> Result<u64, sqlx::Error> {
+        let span = tracing::info_span!(
+            "snare.insert_many",
+            db.system = Backend::CURRENT.system(),
+            db.operation = "INSERT",
+            db.sql.table = %self.table_name,
+            rows = self.rows.len(),
+        );
+
+        async move {
+            if !self.in_transaction {
+                return self.insert_chunks(conn).await;
+            }
+            let mut transaction = conn.begin().await?;
+            let inserted = self.insert_chunks(&mut transaction).await?;
+            transaction.commit().await?;
+
+            Ok(inserted)
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// The statements `execute` runs, in order.
+    pub fn statements(&self) -> Vec<String> {
+        self.chunks()
+            .into_iter()
+            .map(|(statement, _)| statement)
+            .collect()
+    }
+
+    /// Splits the rows into statements, each with the rows it binds.
+    fn chunks(&self) -> Vec<(String, &[T])> {
+        let Some(first) = self.rows.first() else {
+            return Vec::new();
+        };
+        let (columns, _) = first.insert_parts();
+        if columns.is_empty() {
+            // Postgres and SQLite can't insert several rows of defaults in one statement
+            let statement = insert_statement(&self.table_name, "", "");
+            return self
+                .rows
+                .chunks(1)
+                .map(|row| (statement.clone(), row))
+                .collect();
+        }
+        let column_count = columns.split(',').count();
+        let row_bindings = format!("({})", vec!["?"; column_count].join(","));
+        let row_bytes: Vec<usize> = self
+            .rows
+            .iter()
+            .map(|row| row.encoded_len() + row_bindings.len())
+            .collect();
+
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        for end in chunk_ends(
+            &row_bytes,
+            rows_per_statement(column_count, self.max_rows),
+            self.max_bytes,
+        ) {
+            let statement = rebind(&format!(
+                "INSERT INTO {} ({}) VALUES {}",
+                self.table_name,
+                columns,
+                vec![row_bindings.as_str(); end - start].join(",")
+            ))
+            .into_owned();
+            chunks.push((statement, &self.rows[start..end]));
+
+            start = end;
+        }
+
+        chunks
+    }
+
+    async fn insert_chunks(&self, conn: &mut DbConn) -> Result<u64, sqlx::Error> {
+        let mut inserted = 0;
+        for (statement, rows) in self.chunks() {
+            let query = rows
+                .iter()
+                .fold(sqlx::query(&statement), |query, row| row.capture(query));
+            inserted += query.execute(&mut *conn).await?.rows_affected();
+        }
+
+        Ok(inserted)
+    }
+}
+
+/// How many rows of `column_count` values fit in one statement, at most `max_rows` and at least one.
+fn rows_per_statement(column_count: usize, max_rows: usize) -> usize {
+    (Backend::CURRENT.max_bind_parameters() / column_count)
+        .min(max_rows)
+        .max(1)
+}
+
+/// Where each statement's rows end, given the bytes each row binds. A statement takes rows until
+/// it has `max_rows`, or the next one would take it past `max_bytes`. It always takes at least one.
+fn chunk_ends(row_bytes: &[usize], max_rows: usize, max_bytes: usize) -> Vec<usize> {
+    let mut ends = Vec::new();
+    let mut start = 0;
+    while start < row_bytes.len() {
+        let mut end = start;
+        let mut bytes = 0;
+        while end < row_bytes.len() && end - start < max_rows {
+            if end > start && bytes + row_bytes[end] > max_bytes {
+                break;
+            }
+            bytes += row_bytes[end];
+            end += 1;
+        }
+        ends.push(end);
+        start = end;
+    }
+
+    ends
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::placeholder;
+
+    struct Contact {
+        email: String,
+        name: String,
+    }
+
+    impl Table for Contact {}
+
+    impl Ensnarable for Contact {
+        fn insert_parts(&self) -> (String, String) {
+            let bindings = format!("{},{}", placeholder(1), placeholder(2));
+            ("email,name".to_string(), bindings)
+        }
+
+        fn capture<'a>(&'a self, query: SnareQuery<'a>) -> SnareQuery<'a> {
+            query.bind(self.email.clone()).bind(self.name.clone())
+        }
+    }
+
+    impl Upsertable for Contact {
+        fn conflict_columns(&self) -> Vec<&'static str> {
+            vec!["email"]
+        }
+
+        fn upsert_columns(&self) -> Vec<&'static str> {
+            vec!["name"]
+        }
+    }
+
+    #[test]
+    fn upserts_on_the_conflict_columns() {
+        let mut snare = Snare {
+            query: "".to_string(),
+            table_name: "contacts".to_string(),
+            data: Contact {
+                email: "jane@example.com".to_string(),
+                name: "Jane".to_string(),
+            },
+        };
+        let _ = snare.upsert();
+
+        let expected = match Backend::CURRENT {
+            Backend::MySql => {
+                "INSERT INTO contacts (email,name) VALUES (?,?) ON DUPLICATE KEY UPDATE name = VALUES(name)"
+            }
+            Backend::Postgres => {
+                "INSERT INTO contacts (email,name) VALUES ($1,$2) ON CONFLICT (email) DO UPDATE SET name = excluded.name"
+            }
+            Backend::Sqlite => {
+                "INSERT INTO contacts (email,name) VALUES (?,?) ON CONFLICT (email) DO UPDATE SET name = excluded.name"
+            }
+        };
+        assert_eq!(snare.query, expected);
+    }
+
+    struct Counter;
+
+    impl Table for Counter {}
+
+    impl Ensnarable for Counter {
+        fn insert_parts(&self) -> (String, String) {
+            ("".to_string(), "".to_string())
+        }
+
+        fn capture<'a>(&'a self, query: SnareQuery<'a>) -> SnareQuery<'a> {
+            query
+        }
+    }
+
+    #[test]
+    fn inserts_defaults_without_columns() {
+        let mut snare = Snare {
+            query: "".to_string(),
+            table_name: "counters".to_string(),
+            data: Counter,
+        };
+        let _ = snare.insert();
+
+        let expected = match Backend::CURRENT {
+            Backend::MySql => "INSERT INTO counters () VALUES ()",
+            Backend::Postgres | Backend::Sqlite => "INSERT INTO counters DEFAULT VALUES",
+        };
+        assert_eq!(snare.query, expected);
+    }
+
+    #[derive(FromRow)]
+    struct Account {
+        id: i64,
+        name: String,
+        tenant: String,
+    }
+
+    impl Table for Account {}
+
+    impl Updatable for Account {
+        fn assigned_columns(&self) -> Vec<&'static str> {
+            vec!["name", "tenant"]
+        }
+
+        fn capture_assignments<'a>(&'a self, query: SnareQuery<'a>) -> SnareQuery<'a> {
+            query.bind(self.name.clone()).bind(self.tenant.clone())
+        }
+
+        fn key_columns(&self) -> Vec<&'static str> {
+            vec!["id"]
+        }
+
+        fn capture_key<'a>(&'a self, query: SnareQuery<'a>) -> SnareQuery<'a> {
+            query.bind(self.id)
+        }
+    }
+
+    fn account() -> Snare<Account> {
+        Snare {
+            query: "".to_string(),
+            table_name: "accounts".to_string(),
+            data: Account {
+                id: 1,
+                name: "Jane".to_string(),
+                tenant: "acme".to_string(),
+            },
+        }
+    }
+
+    #[test]
+    fn updates_the_assigned_columns_by_primary_key() {
+        let mut snare = account();
+        let _ = snare.update();
+
+        let expected = match Backend::CURRENT {
+            Backend::MySql | Backend::Sqlite => {
+                "UPDATE accounts SET name = ?, tenant = ? WHERE id = ?"
+            }
+            Backend::Postgres => "UPDATE accounts SET name = $1, tenant = $2 WHERE id = $3",
+        };
+        assert_eq!(snare.query, expected);
+    }
+
+    #[test]
+    fn updates_where_the_condition_holds() {
+        let mut snare = account();
+        let _ = snare.update_where("tenant = ? AND name <> ?");
+
+        let expected = match Backend::CURRENT {
+            Backend::MySql | Backend::Sqlite => {
+                "UPDATE accounts SET name = ?, tenant = ? WHERE tenant = ? AND name <> ?"
+            }
+            Backend::Postgres => {
+                "UPDATE accounts SET name = $1, tenant = $2 WHERE tenant = $3 AND name <> $4"
+            }
+        };
+        assert_eq!(snare.query, expected);
+    }
+
+    struct Untouched {
+        id: i64,
+    }
+
+    impl Table for Untouched {}
+
+    impl Updatable for Untouched {
+        fn assigned_columns(&self) -> Vec<&'static str> {
+            vec![]
+        }
+
+        fn capture_assignments<'a>(&'a self, query: SnareQuery<'a>) -> SnareQuery<'a> {
+            query
+        }
+
+        fn key_columns(&self) -> Vec<&'static str> {
+            vec!["id"]
+        }
+
+        fn capture_key<'a>(&'a self, query: SnareQuery<'a>) -> SnareQuery<'a> {
+            query.bind(self.id)
+        }
+    }
+
+    #[test]
+    fn updates_without_assignments_stay_valid() {
+        let mut snare = Snare {
+            query: "".to_string(),
+            table_name: "accounts".to_string(),
+            data: Untouched { id: 1 },
+        };
+        let _ = snare.update();
+
+        let expected = match Backend::CURRENT {
+            Backend::MySql | Backend::Sqlite => "UPDATE accounts SET id = id WHERE id = ?",
+            Backend::Postgres => "UPDATE accounts SET id = id WHERE id = $1",
+        };
+        assert_eq!(snare.query, expected);
+    }
+
+    #[test]
+    fn selects_the_rows_matching_a_filter() {
+        let mut select = Select::<Account>::new("accounts");
+        let _ = select.filter("tenant = ? AND name = ?");
+
+        let expected = match Backend::CURRENT {
+            Backend::MySql | Backend::Sqlite => {
+                "SELECT * FROM accounts WHERE tenant = ? AND name = ?"
+            }
+            Backend::Postgres => "SELECT * FROM accounts WHERE tenant = $1 AND name = $2",
+        };
+        assert_eq!(select.query, expected);
+
+        let _ = select.all();
+        assert_eq!(select.query, "SELECT * FROM accounts");
+    }
+
+    #[derive(FromRow)]
+    struct Note {
+        id: i64,
+        body: String,
+    }
+
+    impl Table for Note {
+        const SOFT_DELETE_COLUMN: Option<&'static str> = Some("deleted_at");
+    }
+
+    impl Ensnarable for Note {
+        fn insert_parts(&self) -> (String, String) {
+            let bindings = format!("{},{}", placeholder(1), placeholder(2));
+            ("id,body".to_string(), bindings)
+        }
+
+        fn capture<'a>(&'a self, query: SnareQuery<'a>) -> SnareQuery<'a> {
+            query.bind(self.id).bind(self.body.clone())
+        }
+    }
+
+    impl Updatable for Note {
+        fn assigned_columns(&self) -> Vec<&'static str> {
+            vec!["body"]
+        }
+
+        fn capture_assignments<'a>(&'a self, query: SnareQuery<'a>) -> SnareQuery<'a> {
+            query.bind(self.body.clone())
+        }
+
+        fn key_columns(&self) -> Vec<&'static str> {
+            vec!["id"]
+        }
+
+        fn capture_key<'a>(&'a self, query: SnareQuery<'a>) -> SnareQuery<'a> {
+            query.bind(self.id)
+        }
+    }
+
+    impl SoftDeletable for Note {
+        const COLUMN: &'static str = "deleted_at";
+    }
+
+    fn note(id: i64, body: &str) -> Snare<Note> {
+        Snare {
+            query: "".to_string(),
+            table_name: "notes".to_string(),
+            data: Note {
+                id,
+                body: body.to_string(),
+            },
+        }
+    }
+
+    #[test]
+    fn selects_leave_soft_deleted_rows_out() {
+        let mut select = Select::<Note>::new("notes");
+        let _ = select.all();
+        assert_eq!(select.query, "SELECT * FROM notes WHERE deleted_at IS NULL");
+
+        let _ = select.filter("body = ?");
+        let expected = match Backend::CURRENT {
+            Backend::MySql | Backend::Sqlite => {
+                "SELECT * FROM notes WHERE (body = ?) AND deleted_at IS NULL"
+            }
+            Backend::Postgres => "SELECT * FROM notes WHERE (body = $1) AND deleted_at IS NULL",
+        };
+        assert_eq!(select.query, expected);
+
+        let mut select = Select::<Note>::new("notes").with_deleted();
+        let _ = select.all();
+        assert_eq!(select.query, "SELECT * FROM notes");
+    }
+
+    #[test]
+    fn soft_deletes_and_restores() {
+        let mut snare = note(1, "Hello");
+
+        let _ = snare.update();
+        let expected = match Backend::CURRENT {
+            Backend::MySql | Backend::Sqlite => {
+                "UPDATE notes SET body = ? WHERE (id = ?) AND deleted_at IS NULL"
+            }
+            Backend::Postgres => {
+                "UPDATE notes SET body = $1 WHERE (id = $2) AND deleted_at IS NULL"
+            }
+        };
+        assert_eq!(snare.query, expected);
+
+        let _ = snare.update_where("body = ?");
+        let expected = match Backend::CURRENT {
+            Backend::MySql | Backend::Sqlite => {
+                "UPDATE notes SET body = ? WHERE (body = ?) AND deleted_at IS NULL"
+            }
+            Backend::Postgres => {
+                "UPDATE notes SET body = $1 WHERE (body = $2) AND deleted_at IS NULL"
+            }
+        };
+        assert_eq!(snare.query, expected);
+
+        let _ = snare.delete();
+        let expected = match Backend::CURRENT {
+            Backend::MySql | Backend::Sqlite => {
+                "UPDATE notes SET deleted_at = CURRENT_TIMESTAMP WHERE id = ? AND deleted_at IS NULL"
+            }
+            Backend::Postgres => {
+                "UPDATE notes SET deleted_at = CURRENT_TIMESTAMP WHERE id = $1 AND deleted_at IS NULL"
+            }
+        };
+        assert_eq!(snare.query, expected);
+
+        let _ = snare.restore();
+        let expected = match Backend::CURRENT {
+            Backend::MySql | Backend::Sqlite => "UPDATE notes SET deleted_at = NULL WHERE id = ?",
+            Backend::Postgres => "UPDATE notes SET deleted_at = NULL WHERE id = $1",
+        };
+        assert_eq!(snare.query, expected);
+    }
+
+    #[test]
+    fn hard_deletes_without_soft_delete() {
+        let mut snare = account();
+        let _ = snare.delete();
+
+        let expected = match Backend::CURRENT {
+            Backend::MySql | Backend::Sqlite => "DELETE FROM accounts WHERE id = ?",
+            Backend::Postgres => "DELETE FROM accounts WHERE id = $1",
+        };
+        assert_eq!(snare.query, expected);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn soft_deleted_rows_are_hidden_and_left_alone() {
+        crate::store::with_memory_db(|pool| async move {
+            sqlx::query("CREATE TABLE notes (id INTEGER PRIMARY KEY, body TEXT, deleted_at TEXT)")
+                .execute(&pool)
+                .await
+                .unwrap();
+            for (id, body) in [(1, "Kept"), (2, "Deleted")] {
+                note(id, body).insert().execute(&pool).await.unwrap();
+            }
+            let deleted = note(2, "Deleted").delete().execute(&pool).await.unwrap();
+            assert_eq!(deleted.rows_affected(), 1);
+
+            let notes = Select::<Note>::new("notes")
+                .all()
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+            assert_eq!(notes.iter().map(|n| n.id).collect::<Vec<_>>(), vec![1]);
+            let notes = Select::<Note>::new("notes")
+                .filter("body = ?")
+                .bind("Deleted")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+            assert!(notes.is_empty());
+            let notes = Select::<Note>::new("notes")
+                .with_deleted()
+                .all()
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+            assert_eq!(notes.len(), 2);
+
+            let updated = note(2, "Edited").update().execute(&pool).await.unwrap();
+            assert_eq!(updated.rows_affected(), 0);
+            let updated = note(0, "Edited")
+                .update_where("id > ?")
+                .bind(0)
+                .execute(&pool)
+                .await
+                .unwrap();
+            assert_eq!(updated.rows_affected(), 1);
+
+            note(2, "Deleted").restore().execute(&pool).await.unwrap();
+            let updated = note(2, "Edited").update().execute(&pool).await.unwrap();
+            assert_eq!(updated.rows_affected(), 1);
+        })
+        .await;
+    }
+
+    #[test]
+    fn statements_stay_under_the_bind_parameter_limit() {
+        let limit = Backend::CURRENT.max_bind_parameters();
+
+        assert_eq!(rows_per_statement(1, usize::MAX), limit);
+        assert_eq!(rows_per_statement(3, usize::MAX), limit / 3);
+        assert!(rows_per_statement(7, usize::MAX) * 7 <= limit);
+        assert!((rows_per_statement(7, usize::MAX) + 1) * 7 > limit);
+        assert_eq!(rows_per_statement(limit, usize::MAX), 1);
+        assert_eq!(rows_per_statement(limit + 1, usize::MAX), 1);
+    }
+
+    #[test]
+    fn statements_stay_under_max_rows() {
+        assert_eq!(rows_per_statement(3, 100), 100);
+        assert_eq!(chunk_ends(&[1; 7], 3, usize::MAX), vec![3, 6, 7]);
+        assert_eq!(chunk_ends(&[1; 6], 3, usize::MAX), vec![3, 6]);
+        assert_eq!(chunk_ends(&[1; 6], 1, usize::MAX), vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!(chunk_ends(&[], 3, usize::MAX), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn statements_stay_under_max_bytes() {
+        // Exactly at the limit still fits, one byte over starts the next statement
+        assert_eq!(chunk_ends(&[5, 5, 5, 5], usize::MAX, 10), vec![2, 4]);
+        assert_eq!(chunk_ends(&[5, 6, 5, 5], usize::MAX, 10), vec![1, 2, 4]);
+        // A row bigger than the limit still goes out, on its own
+        assert_eq!(chunk_ends(&[3, 20, 3, 3], usize::MAX, 10), vec![1, 2, 4]);
+        // Whichever of max_rows and max_bytes is hit first ends the statement
+        assert_eq!(chunk_ends(&[2; 6], 2, 10), vec![2, 4, 6]);
+        assert_eq!(chunk_ends(&[4; 6], 5, 10), vec![2, 4, 6]);
+    }
 }
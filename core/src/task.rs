@@ -0,0 +1,213 @@
+use std::{
+    any::TypeId,
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use futures::Stream;
+use tokio::task::JoinHandle;
+use tracing::{Instrument, Span};
+
+use crate::{
+    middleware::{deadline::Deadline, request_context::RequestContext},
+    MapKey, REQUEST_CONTEXT, SERVER_CONTEXT,
+};
+
+/// The cali contexts of the current task, so they can be carried into another one. Tokio doesn't
+/// carry task locals over by itself, so without them `get_context` and `get_conn` fail in a
+/// spawned task.
+#[derive(Debug, Clone, Default)]
+pub struct TaskContext {
+    server: Option<Arc<HashMap<TypeId, MapKey>>>,
+    request: Option<RequestContext>,
+}
+
+impl TaskContext {
+    /// Captures the contexts of the current task, empty outside of a request.
+    pub fn current() -> Self {
+        Self {
+            server: SERVER_CONTEXT.try_with(|ctx| ctx.clone()).ok(),
+            request: REQUEST_CONTEXT.try_with(|ctx| ctx.clone()).ok(),
+        }
+    }
+
+    /// Captures the contexts of the current task for work that outlives the request. The request
+    /// context is a copy without the request's deadline, so `get_conn` keeps working after the
+    /// caller has been answered.
+    pub fn detached() -> Self {
+        let request = REQUEST_CONTEXT
+            .try_with(|ctx| {
+                let request = ctx.detached();
+                request.remove::<Deadline>();
+                request
+            })
+            .ok();
+
+        Self {
+            server: SERVER_CONTEXT.try_with(|ctx| ctx.clone()).ok(),
+            request,
+        }
+    }
+
+    /// Runs `future` with the captured contexts.
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        match (self.server, self.request) {
+            (Some(server), Some(request)) => {
+                SERVER_CONTEXT
+                    .scope(server, REQUEST_CONTEXT.scope(request, future))
+                    .await
+            }
+            (Some(server), None) => SERVER_CONTEXT.scope(server, future).await,
+            (None, Some(request)) => REQUEST_CONTEXT.scope(request, future).await,
+            (None, None) => future.await,
+        }
+    }
+
+    /// Runs `f` with the captured contexts.
+    pub fn sync_scope<R>(self, f: impl FnOnce() -> R) -> R {
+        match (self.server, self.request) {
+            (Some(server), Some(request)) => {
+                SERVER_CONTEXT.sync_scope(server, || REQUEST_CONTEXT.sync_scope(request, f))
+            }
+            (Some(server), None) => SERVER_CONTEXT.sync_scope(server, f),
+            (None, Some(request)) => REQUEST_CONTEXT.sync_scope(request, f),
+            (None, None) => f(),
+        }
+    }
+}
+
+/// `tokio::spawn`, but the task keeps the cali contexts and tracing span of the caller. The task
+/// gets a copy of the request context without the request's deadline, see `TaskContext::detached`,
+/// so it can carry on after the request is answered. Use `spawn_scoped` for work that belongs to
+/// the request.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let context = TaskContext::detached();
+
+    tokio::spawn(context.scope(future).instrument(Span::current()))
+}
+
+/// `spawn` for work the request waits on. The task shares the request context with the request
+/// it was spawned from, so the request's deadline still applies to `get_conn` in the task.
+pub fn spawn_scoped<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let context = TaskContext::current();
+
+    tokio::spawn(context.scope(future).instrument(Span::current()))
+}
+
+/// `tokio::task::spawn_blocking`, but `f` runs with the cali contexts and tracing span of the
+/// caller. Like `spawn`, without the request's deadline.
+pub fn spawn_blocking<F, R>(f: F) -> JoinHandle<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let context = TaskContext::detached();
+    let span = Span::current();
+
+    tokio::task::spawn_blocking(move || span.in_scope(|| context.sync_scope(f)))
+}
+
+/// Polls `stream` with the cali contexts of the caller. Tonic polls the stream returned by a
+/// server-streaming handler outside of the request's task, so wrap it in this when producing
+/// items needs `get_context` or `get_conn`.
+pub fn stream<S: Stream>(stream: S) -> ContextStream<S> {
+    ContextStream {
+        inner: Box::pin(stream),
+        context: TaskContext::current(),
+    }
+}
+
+pub struct ContextStream<S> {
+    inner: Pin<Box<S>>,
+    context: TaskContext,
+}
+
+impl<S: Stream> Stream for ContextStream<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let context = self.context.clone();
+        context.sync_scope(|| self.inner.as_mut().poll_next(cx))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use super::*;
+    use crate::helpers::{get_deadline, get_request_id, insert_request_context};
+
+    /// Runs `test` in a request with a request id and a deadline that's already passed.
+    async fn in_request<F: Future>(test: F) -> F::Output {
+        REQUEST_CONTEXT
+            .scope(RequestContext::default(), async move {
+                insert_request_context(crate::middleware::request_id::RequestId(
+                    "request-1".to_string(),
+                ))
+                .unwrap();
+                insert_request_context(Deadline(Instant::now() - Duration::from_secs(1))).unwrap();
+                test.await
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn spawn_leaves_the_deadline_behind() {
+        in_request(async {
+            let (request_id, deadline) = spawn(async { (get_request_id(), get_deadline()) })
+                .await
+                .unwrap();
+            assert_eq!(request_id.as_deref(), Some("request-1"));
+            assert_eq!(deadline, None);
+
+            let (request_id, deadline) = spawn_blocking(|| (get_request_id(), get_deadline()))
+                .await
+                .unwrap();
+            assert_eq!(request_id.as_deref(), Some("request-1"));
+            assert_eq!(deadline, None);
+
+            assert!(spawn_scoped(async { get_deadline() })
+                .await
+                .unwrap()
+                .is_some());
+            // The request keeps its own deadline
+            assert!(get_deadline().is_some());
+        })
+        .await
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn spawned_tasks_get_connections_after_the_deadline() {
+        crate::store::with_memory_db(|pool| async move {
+            // The pool's only connection is busy until the request's deadline has passed
+            let busy = pool.acquire().await.unwrap();
+            insert_request_context(Deadline(Instant::now() + Duration::from_millis(20))).unwrap();
+
+            let conn = spawn(async { crate::store::try_get_conn().await.map(|_| ()) });
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(busy);
+
+            assert!(conn.await.unwrap().is_ok());
+        })
+        .await
+    }
+}
@@ -17,12 +17,16 @@
 extern crate proc_macro;
 use std::path::Path;
 
-use cali_core::protos::parser::get_proto_data;
+use cali_core::protos::parser::{get_proto_data, get_proto_messages};
 use convert_case::{Case, Casing};
 use proc_macro::TokenStream;
 use proc_macro2::{Ident, Span, TokenStream as TokenStream2};
-use quote::quote;
-use syn::{parse_macro_input, DeriveInput};
+use quote::{quote, quote_spanned};
+use syn::{
+    ext::IdentExt,
+    parse::{Parse, ParseStream},
+    parse_macro_input, spanned::Spanned, DeriveInput, FieldsNamed,
+};
 
 /// This is typically used inside a project's build.rs file. It takes no arguments and serves as a
 /// convenience that automatically generates the necessary tonic code to generate rust code from
@@ -64,31 +68,266 @@ pub fn autogen_protos(_item: TokenStream) -> TokenStream {
     gen.into()
 }
 
+struct ControllerInput {
+    name: Ident,
+    dependencies: Option<FieldsNamed>,
+}
+
+impl Parse for ControllerInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let name = input.parse()?;
+        let dependencies = if input.is_empty() {
+            None
+        } else {
+            Some(input.parse()?)
+        };
+
+        Ok(Self { name, dependencies })
+    }
+}
+
 /// This procedural macro is typically used at the top of a controller file to instantiate a struct
 /// for which the proto service's generated rust trait will be implemented. Usually this is
 /// generated from cali_cli.
+///
+/// The controller can declare its dependencies as fields. `setup_server!` builds it at startup
+/// through `cali_core::context::FromContext`, resolving every field from the registered context,
+/// and tests can pass their own through `new`:
+/// ```rust
+/// controller!(AccountController {
+///     accounts: AccountRepo,
+///     config: Arc<Config>,
+/// });
+/// ```
 #[proc_macro]
 pub fn controller(input: TokenStream) -> TokenStream {
-    let controller_struct_name = Ident::new(&format!("{}", input)[..], Span::call_site());
+    let ControllerInput { name, dependencies } = parse_macro_input!(input as ControllerInput);
+    let dependencies = dependencies.unwrap_or_else(|| syn::parse_quote!({}));
+    let field_names: Vec<&Ident> = dependencies
+        .named
+        .iter()
+        .filter_map(|field| field.ident.as_ref())
+        .collect();
+    let field_types = dependencies.named.iter().map(|field| &field.ty);
 
     let gen = quote! {
         #[derive(Clone)]
-        pub struct #controller_struct_name {}
+        pub struct #name #dependencies
+
+        impl #name {
+            pub fn new(#(#field_names: #field_types),*) -> Self {
+                #name { #(#field_names),* }
+            }
+        }
 
-        impl #controller_struct_name {
-            pub fn new() -> Self {
-                #controller_struct_name {}
+        impl cali_core::context::FromContext for #name {
+            #[allow(unused_variables)]
+            fn from_context(
+                context: &cali_core::context::GlobalContext,
+            ) -> Result<Self, cali_core::helpers::ContextError> {
+                Ok(#name {
+                    #(#field_names: context.resolve()?),*
+                })
             }
         }
     };
     gen.into()
 }
 
+struct EnsnareField {
+    ident: Ident,
+    ty: syn::Type,
+    column: String,
+    primary_key: bool,
+    upsert: bool,
+    skip: bool,
+    skip_insert: bool,
+    auto_increment: bool,
+    json: bool,
+}
+
+impl EnsnareField {
+    /// Whether the column is written by inserts and upserts.
+    fn inserted(&self) -> bool {
+        !self.skip && !self.skip_insert && !self.auto_increment
+    }
+
+    /// Binds `value`, as JSON for `#[snare(json)]` fields.
+    fn bound(&self, value: TokenStream2) -> TokenStream2 {
+        if self.json {
+            quote!(cali_core::store::snare::Json(#value))
+        } else {
+            value
+        }
+    }
+}
+
+#[derive(Default)]
+struct EnsnareOptions {
+    table: Option<String>,
+    soft_delete: Option<String>,
+    on_conflict: Vec<String>,
+}
+
+fn ensnare_options(attrs: &[syn::Attribute]) -> syn::Result<EnsnareOptions> {
+    let mut options = EnsnareOptions::default();
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("snare")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("table") {
+                let table: syn::LitStr = meta.value()?.parse()?;
+                if table.value().is_empty() {
+                    return Err(syn::Error::new(table.span(), "Expected a table name"));
+                }
+                options.table = Some(table.value());
+                Ok(())
+            } else if meta.path.is_ident("soft_delete") {
+                let column: syn::LitStr = meta.value()?.parse()?;
+                options.soft_delete = Some(column.value());
+                Ok(())
+            } else if meta.path.is_ident("on_conflict") {
+                let columns: syn::LitStr = meta.value()?.parse()?;
+                options.on_conflict = columns
+                    .value()
+                    .split(',')
+                    .map(|column| column.trim().to_string())
+                    .filter(|column| !column.is_empty())
+                    .collect();
+                if options.on_conflict.is_empty() {
+                    return Err(syn::Error::new(columns.span(), "Expected a list of columns"));
+                }
+                Ok(())
+            } else {
+                Err(meta.error("Unknown snare attribute"))
+            }
+        })?;
+    }
+
+    Ok(options)
+}
+
+fn ensnare_fields(fields: &FieldsNamed) -> syn::Result<Vec<EnsnareField>> {
+    fields
+        .named
+        .iter()
+        .map(|field| {
+            let ident = field.ident.clone().expect("Named fields have an ident");
+            let mut ensnare_field = EnsnareField {
+                column: ident.unraw().to_string(),
+                ident,
+                ty: field.ty.clone(),
+                primary_key: false,
+                upsert: false,
+                skip: false,
+                skip_insert: false,
+                auto_increment: false,
+                json: false,
+            };
+            for attr in field.attrs.iter().filter(|attr| attr.path().is_ident("snare")) {
+                attr.parse_nested_meta(|meta| {
+                    if meta.path.is_ident("primary_key") {
+                        ensnare_field.primary_key = true;
+                        Ok(())
+                    } else if meta.path.is_ident("upsert") {
+                        ensnare_field.upsert = true;
+                        Ok(())
+                    } else if meta.path.is_ident("rename") {
+                        let column: syn::LitStr = meta.value()?.parse()?;
+                        if column.value().is_empty() {
+                            return Err(syn::Error::new(column.span(), "Expected a column name"));
+                        }
+                        ensnare_field.column = column.value();
+                        Ok(())
+                    } else if meta.path.is_ident("skip") {
+                        ensnare_field.skip = true;
+                        Ok(())
+                    } else if meta.path.is_ident("skip_insert") {
+                        ensnare_field.skip_insert = true;
+                        Ok(())
+                    } else if meta.path.is_ident("auto_increment") {
+                        ensnare_field.auto_increment = true;
+                        Ok(())
+                    } else if meta.path.is_ident("json") {
+                        ensnare_field.json = true;
+                        Ok(())
+                    } else {
+                        Err(meta.error("Unknown snare attribute"))
+                    }
+                })?;
+            }
+
+            if ensnare_field.skip && (ensnare_field.primary_key || ensnare_field.upsert) {
+                return Err(syn::Error::new_spanned(
+                    field,
+                    "A skipped field can't be a primary key or upsert column",
+                ));
+            }
+            if ensnare_field.primary_key && ensnare_field.upsert {
+                return Err(syn::Error::new_spanned(
+                    field,
+                    "A primary key can't be an upsert column",
+                ));
+            }
+            if (ensnare_field.skip_insert || ensnare_field.auto_increment) && ensnare_field.upsert {
+                return Err(syn::Error::new_spanned(
+                    field,
+                    "An upsert column has to be inserted",
+                ));
+            }
+            if ensnare_field.auto_increment && (ensnare_field.skip || ensnare_field.json) {
+                return Err(syn::Error::new_spanned(
+                    field,
+                    "An auto increment field can't be skipped or json",
+                ));
+            }
+            Ok(ensnare_field)
+        })
+        .collect()
+}
+
+/// The conflict columns of an upsert and the columns it overwrites, `None` when there's nothing to
+/// conflict on. Without `#[snare(upsert)]` fields every inserted column is overwritten, except the
+/// conflict columns and the primary key.
+fn upsert_columns(
+    fields: &[EnsnareField],
+    options: &EnsnareOptions,
+) -> Option<(Vec<String>, Vec<String>)> {
+    let conflict_columns: Vec<String> = if options.on_conflict.is_empty() {
+        fields
+            .iter()
+            .filter(|f| f.primary_key && !f.skip)
+            .map(|f| f.column.clone())
+            .collect()
+    } else {
+        options.on_conflict.clone()
+    };
+    if conflict_columns.is_empty() {
+        return None;
+    }
+
+    let marked: Vec<String> = fields
+        .iter()
+        .filter(|f| f.upsert)
+        .map(|f| f.column.clone())
+        .collect();
+    let upsert_columns = if marked.is_empty() {
+        fields
+            .iter()
+            .filter(|f| f.inserted() && !f.primary_key)
+            .map(|f| f.column.clone())
+            .filter(|column| !conflict_columns.contains(column))
+            .collect()
+    } else {
+        marked
+    };
+    Some((conflict_columns, upsert_columns))
+}
+
 /// Snare is Cali's very simple ORM/convenience around sqlx. You can Ensnare your structs that map
 /// to database table's by including it in your derive directives.
 /// ```rust
 /// #[derive(Clone, FromRow, Ensnare)]
 /// pub struct Account {
+///     #[snare(primary_key)]
 ///     pub id: i64,
 ///     pub name: String,
 ///     pub email: String,
@@ -99,78 +338,379 @@ pub fn controller(input: TokenStream) -> TokenStream {
 /// From here you should now have trap/1 available on your structs. Trap returns a wrapping type
 /// `Snare<T>` that allows you to generate insert statements with easy:
 /// ```rust
-/// let result = account.trap("accounts").insert().execute(conn).await?;
+/// let result = account.trap("accounts").execute_insert(conn).await?;
+/// ```
+///
+/// The `execute_*` helpers run the statement inside a `snare.*` tracing span. `insert()` and the
+/// other `Ensnared` methods only build the sqlx query, and running it yourself emits no span.
+///
+/// Name the table once with `#[snare(table = "accounts")]` on the struct, and `trap()`,
+/// `select()` and `insert_many(rows)` take no table name. Fields map to the column of the same
+/// name, these field attributes change that:
+/// - `#[snare(rename = "email_address")]` writes the field to another column.
+/// - `#[snare(skip)]` leaves the field out of every statement.
+/// - `#[snare(skip_insert)]` leaves it out of inserts, so the database default is used, but still
+///   writes it on updates.
+/// - `#[snare(json)]` writes the field as JSON, it has to implement `serde::Serialize`.
+/// - `#[snare(auto_increment)]` leaves the id out of inserts, so the database generates it.
+///
+/// `account.trap("accounts").insert_returning(&mut conn)` inserts the row and returns it as it
+/// was stored, with the generated id and column defaults filled in. The struct needs a primary
+/// key for it, MySQL reads the row back by it.
+///
+/// Rows are read back through sqlx's `FromRow`, give those fields the matching `#[sqlx(rename =
+/// "...")]`, `#[sqlx(skip)]` or `#[sqlx(json)]` as well.
+///
+/// Structs with a `#[snare(primary_key)]` field, or several for a composite key, can also be
+/// updated. `update` writes every other field, while the generated changeset, `AccountChangeset`
+/// here, only writes the fields set on it:
+/// ```rust
+/// account.trap("accounts").execute_update(conn).await?;
+/// AccountChangeset::new(id)
+///     .set_email(email)
+///     .trap("accounts")
+///     .execute_update(conn)
+///     .await?;
+/// ```
+///
+/// Keyed rows are removed with `delete`. Mark the struct with `#[snare(soft_delete =
+/// "deleted_at")]` to have `delete` set that column to the current time instead, and `restore`
+/// clear it again. `update` leaves deleted rows alone. `Account::select("accounts")` builds selects
+/// that leave out deleted rows, call `with_deleted` on it to include them:
+/// ```rust
+/// let account = Account::select("accounts")
+///     .filter("email = ?")
+///     .bind(email)
+///     .fetch_optional(conn)
+///     .await?;
 /// ```
-#[proc_macro_derive(Ensnare)]
+///
+/// `Account::insert_many(accounts, "accounts").execute(&mut conn)` inserts any number of rows with
+/// multi-row inserts, chunked to stay under the database's limits.
+///
+/// When no field is inserted, e.g. a struct with only an auto increment id, inserts write a row of
+/// column defaults.
+///
+/// `upsert` inserts the row, or overwrites the existing row it conflicts with. The conflict is
+/// on the primary key, or on the columns in `#[snare(on_conflict = "email")]`. Every other column
+/// but the primary key is overwritten, unless some fields are marked `#[snare(upsert)]`, then only
+/// those are.
+///
+/// The statement uses the bind parameters of the database backend cali_core was built for, `?`
+/// for MySQL and SQLite, `$1` for Postgres.
+#[proc_macro_derive(Ensnare, attributes(snare))]
 pub fn derive_ensnare(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
+    match expand_ensnare(input) {
+        Ok(expanded) => expanded.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+fn expand_ensnare(input: DeriveInput) -> syn::Result<TokenStream2> {
     let struct_name = input.ident;
+    let visibility = input.vis;
+    let options = ensnare_options(&input.attrs)?;
+    let soft_delete_column = match &options.soft_delete {
+        Some(column) => quote!(Some(#column)),
+        None => quote!(None),
+    };
 
     let struct_fields = match input.data {
         syn::Data::Struct(syn::DataStruct {
             fields: syn::Fields::Named(named_fields),
             ..
-        }) => named_fields
-            .named
-            .iter()
-            .filter_map(|f| f.ident.clone())
-            .collect::<Vec<Ident>>(),
+        }) => ensnare_fields(&named_fields)?,
         _ => {
-            panic!("Can only Ensnare struct types");
+            return Err(syn::Error::new_spanned(
+                &struct_name,
+                "Ensnare can only be derived for structs with named fields",
+            ))
         }
     };
 
-    let bind_points = struct_fields
+    let auto_increment = match struct_fields
         .iter()
-        .map(|_| "?".to_string())
-        .collect::<Vec<String>>()
-        .join(",");
+        .filter(|f| f.auto_increment)
+        .collect::<Vec<&EnsnareField>>()
+        .as_slice()
+    {
+        [] => quote!(),
+        [field] => {
+            let ident = &field.ident;
+            let ty = &field.ty;
+            // Spanned to the field, so a type without `TryFrom<u64>` is reported there
+            let id = quote_spanned! {ty.span()=>
+                <#ty as ::std::convert::TryFrom<u64>>::try_from(id)
+            };
+            quote! {
+                fn set_auto_increment(&mut self, id: u64) {
+                    self.#ident = #id.expect("The auto increment id doesn't fit the field");
+                }
+            }
+        }
+        [_, field, ..] => {
+            return Err(syn::Error::new_spanned(
+                &field.ident,
+                "Only one field can be auto increment",
+            ))
+        }
+    };
+
+    let inserted_fields: Vec<&EnsnareField> =
+        struct_fields.iter().filter(|f| f.inserted()).collect();
 
-    let fields = struct_fields
+    let field_count = inserted_fields.len();
+
+    let fields = inserted_fields
         .iter()
-        .map(|f| f.to_string())
-        .collect::<Vec<String>>()
+        .map(|f| f.column.as_str())
+        .collect::<Vec<&str>>()
         .join(",");
 
-    let bindings: Vec<TokenStream2> = struct_fields
+    let bindings: Vec<TokenStream2> = inserted_fields
+        .iter()
+        .map(|f| {
+            let ident = &f.ident;
+            let value = f.bound(quote!(self.#ident.clone()));
+            quote!(bind(#value))
+        })
+        .collect();
+
+    let encoded: Vec<TokenStream2> = inserted_fields
         .iter()
-        .map(|f| quote!(bind(self.#f.clone())))
+        .map(|f| {
+            let ident = &f.ident;
+            f.bound(quote!(&self.#ident))
+        })
         .collect();
 
-    let expanded = quote! {
-    impl cali_core::store::snare::Ensnarable for #struct_name {
+    // With a table name on the struct, `trap` and `select` don't take one
+    let (table_param, table_name) = match &options.table {
+        Some(table) => (quote!(), quote!(#table)),
+        None => (quote!(table_name: &str), quote!(table_name)),
+    };
+
+    let mut expanded = quote! {
+    impl cali_core::store::snare::Table for #struct_name {
+                const SOFT_DELETE_COLUMN: Option<&'static str> = #soft_delete_column;
+            }
+
+            impl cali_core::store::snare::Ensnarable for #struct_name {
                 fn insert_parts(&self) -> (String, String) {
-                    (#fields.to_string(), #bind_points.to_string())
+                    let bind_points = (1..=#field_count)
+                        .map(cali_core::store::placeholder)
+                        .collect::<Vec<String>>()
+                        .join(",");
+                    (#fields.to_string(), bind_points)
                 }
 
                 fn capture<'a>(
                     &'a self,
-                    query: sqlx::query::Query<
-                        'a,
-                        sqlx::MySql,
-                        <sqlx::MySql as sqlx::Database>::Arguments<'_>,
-                    >,
-                ) -> sqlx::query::Query<
-                    'a,
-                    sqlx::MySql,
-                    <sqlx::MySql as sqlx::Database>::Arguments<'_>,
-                > {
-                    query.#(#bindings).*
+                    query: cali_core::store::snare::SnareQuery<'a>,
+                ) -> cali_core::store::snare::SnareQuery<'a> {
+                    query #(.#bindings)*
+                }
+
+                fn encoded_len(&self) -> usize {
+                    0 #(+ cali_core::store::encoded_len(&#encoded))*
                 }
+
+                #auto_increment
             }
 
             impl #struct_name {
-                pub fn trap(self, table_name: &str) -> cali_core::store::snare::Snare<#struct_name> {
+                pub fn trap(self, #table_param) -> cali_core::store::snare::Snare<#struct_name> {
                     cali_core::store::snare::Snare {
                         query: "".to_string(),
-                        table_name: table_name.to_string(),
+                        table_name: #table_name.to_string(),
                         data: self,
                     }
                 }
+
+                pub fn select(#table_param) -> cali_core::store::snare::Select<#struct_name> {
+                    cali_core::store::snare::Select::new(#table_name)
+                }
+
+                pub fn insert_many(
+                    rows: Vec<#struct_name>,
+                    #table_param
+                ) -> cali_core::store::snare::InsertMany<#struct_name> {
+                    cali_core::store::snare::Snare::insert_many(rows, #table_name)
+                }
             }
         };
 
-    TokenStream::from(expanded)
+    let (key_fields, value_fields): (Vec<&EnsnareField>, Vec<&EnsnareField>) = struct_fields
+        .iter()
+        .filter(|f| !f.skip)
+        .partition(|f| f.primary_key);
+
+    if let Some((conflict_columns, upsert_columns)) = upsert_columns(&struct_fields, &options) {
+        expanded.extend(quote! {
+            impl cali_core::store::snare::Upsertable for #struct_name {
+                fn conflict_columns(&self) -> Vec<&'static str> {
+                    vec![#(#conflict_columns),*]
+                }
+
+                fn upsert_columns(&self) -> Vec<&'static str> {
+                    vec![#(#upsert_columns),*]
+                }
+            }
+        });
+    }
+    if !key_fields.is_empty() {
+        let changeset = Ident::new(&format!("{}Changeset", struct_name), Span::call_site());
+        let key_idents: Vec<&Ident> = key_fields.iter().map(|f| &f.ident).collect();
+        let key_types: Vec<&syn::Type> = key_fields.iter().map(|f| &f.ty).collect();
+        let key_columns: Vec<&str> = key_fields.iter().map(|f| f.column.as_str()).collect();
+        let key_values: Vec<TokenStream2> = key_fields
+            .iter()
+            .map(|f| {
+                let ident = &f.ident;
+                f.bound(quote!(self.#ident.clone()))
+            })
+            .collect();
+        let value_idents: Vec<&Ident> = value_fields.iter().map(|f| &f.ident).collect();
+        let value_types: Vec<&syn::Type> = value_fields.iter().map(|f| &f.ty).collect();
+        let value_columns: Vec<&str> = value_fields.iter().map(|f| f.column.as_str()).collect();
+        let values: Vec<TokenStream2> = value_fields
+            .iter()
+            .map(|f| {
+                let ident = &f.ident;
+                f.bound(quote!(self.#ident.clone()))
+            })
+            .collect();
+        let changed_values: Vec<TokenStream2> = value_fields
+            .iter()
+            .map(|f| f.bound(quote!(value.clone())))
+            .collect();
+        let setters: Vec<Ident> = value_idents
+            .iter()
+            .map(|f| Ident::new(&format!("set_{}", f.unraw()), Span::call_site()))
+            .collect();
+
+        expanded.extend(quote! {
+            impl cali_core::store::snare::Updatable for #struct_name {
+                fn assigned_columns(&self) -> Vec<&'static str> {
+                    vec![#(#value_columns),*]
+                }
+
+                fn capture_assignments<'a>(
+                    &'a self,
+                    query: cali_core::store::snare::SnareQuery<'a>,
+                ) -> cali_core::store::snare::SnareQuery<'a> {
+                    query #(.bind(#values))*
+                }
+
+                fn key_columns(&self) -> Vec<&'static str> {
+                    vec![#(#key_columns),*]
+                }
+
+                fn capture_key<'a>(
+                    &'a self,
+                    query: cali_core::store::snare::SnareQuery<'a>,
+                ) -> cali_core::store::snare::SnareQuery<'a> {
+                    query #(.bind(#key_values))*
+                }
+            }
+
+            impl #struct_name {
+                /// A changeset for this row that doesn't change anything yet.
+                pub fn changeset(&self) -> #changeset {
+                    #changeset::new(#(self.#key_idents.clone()),*)
+                }
+            }
+
+            /// The primary key of a row and the fields to change on it, fields left at `None`
+            /// aren't written by `update`.
+            #[derive(Clone)]
+            #visibility struct #changeset {
+                #(pub #key_idents: #key_types,)*
+                #(pub #value_idents: Option<#value_types>,)*
+            }
+
+            impl #changeset {
+                pub fn new(#(#key_idents: #key_types),*) -> Self {
+                    #changeset {
+                        #(#key_idents,)*
+                        #(#value_idents: None,)*
+                    }
+                }
+
+                #(
+                    pub fn #setters(mut self, value: #value_types) -> Self {
+                        self.#value_idents = Some(value);
+                        self
+                    }
+                )*
+
+                pub fn trap(self, #table_param) -> cali_core::store::snare::Snare<#changeset> {
+                    cali_core::store::snare::Snare {
+                        query: "".to_string(),
+                        table_name: #table_name.to_string(),
+                        data: self,
+                    }
+                }
+            }
+
+            impl cali_core::store::snare::Table for #changeset {
+                const SOFT_DELETE_COLUMN: Option<&'static str> = #soft_delete_column;
+            }
+
+            impl cali_core::store::snare::Updatable for #changeset {
+                fn assigned_columns(&self) -> Vec<&'static str> {
+                    #[allow(unused_mut)]
+                    let mut columns = Vec::new();
+                    #(
+                        if self.#value_idents.is_some() {
+                            columns.push(#value_columns);
+                        }
+                    )*
+                    columns
+                }
+
+                fn capture_assignments<'a>(
+                    &'a self,
+                    query: cali_core::store::snare::SnareQuery<'a>,
+                ) -> cali_core::store::snare::SnareQuery<'a> {
+                    #[allow(unused_mut)]
+                    let mut query = query;
+                    #(
+                        if let Some(value) = &self.#value_idents {
+                            query = query.bind(#changed_values);
+                        }
+                    )*
+                    query
+                }
+
+                fn key_columns(&self) -> Vec<&'static str> {
+                    vec![#(#key_columns),*]
+                }
+
+                fn capture_key<'a>(
+                    &'a self,
+                    query: cali_core::store::snare::SnareQuery<'a>,
+                ) -> cali_core::store::snare::SnareQuery<'a> {
+                    query #(.bind(#key_values))*
+                }
+            }
+        });
+
+        if let Some(column) = &options.soft_delete {
+            expanded.extend(quote! {
+                impl cali_core::store::snare::SoftDeletable for #struct_name {
+                    const COLUMN: &'static str = #column;
+                }
+
+                impl cali_core::store::snare::SoftDeletable for #changeset {
+                    const COLUMN: &'static str = #column;
+                }
+            });
+        }
+    }
+
+    Ok(expanded)
 }
 
 /// This is the main magic macro of cali, usually found in the entry/main.rs of the web crate.
@@ -179,12 +719,34 @@ pub fn derive_ensnare(input: TokenStream) -> TokenStream {
 /// argument is your own server config. 
 ///
 /// The server config makes use of the builder pattern to enable opt in features of the framework.
-/// At this stage, there are three opt in features:
+/// At this stage, there are these opt in features:
 /// - Enable database using `.enable_database()`
+/// - Enable tokio console using `.enable_tokio_console()`
 /// - Add custom Tower middleware using `.add_middleware(setup_fn: impl FnOnce(Server<CaliBaseTowerStack>) -> Server<ResultTowerStack> + 'static)`
 /// - Create your own globally available context using the `.add_global_context(your_context: T)`
 ///   where T is the server context type defined in your application code. This isn't optional, but
 ///   can be left to a empty struct with very little consequence.
+/// - Share any number of other values, like clients and caches, using `.provide(value)`, once per
+///   type. Read them back with `get_context::<V>()`.
+///
+/// Every RPC is wrapped in a `grpc.request` span that continues the caller's W3C trace context.
+/// Spans are exported according to the `tracing` section of your config file. Requests are also
+/// tagged with an `x-request-id`, which is echoed back to the caller and added to every log line.
+/// When the config file has an `auth` section, calls need a valid JWT bearer token, or an API key
+/// created with `cali keys create` when `auth.api-keys` is set. Roles declared on an rpc with
+/// `option (cali.auth) = { roles: ["admin"] };` are checked before the controller runs. The
+/// `rate-limit` section limits calls per method and per caller. Calls are answered with
+/// `DeadlineExceeded` once the caller's `grpc-timeout` or the method's timeout from the `timeouts`
+/// section passes. Panics in controllers are answered with `Status::internal`, unless
+/// `.disable_panic_catching()` is called on the CaliConfig. Every call is written to the access log,
+/// see the `access-log` config section, with fields marked `[(cali.sensitive) = true]` redacted.
+/// Transport limits, keepalive and compression are set in the `server` section.
+///
+/// The server itself is assembled by `cali_core::server::CaliServer`, this macro only loads the
+/// config, sets up logging and registers the controllers it finds in `interface/grpc/services`.
+/// Controllers are built with `FromContext` once the database is connected, so they can hold any
+/// of the registered context.
+/// Use `CaliServer` directly if you want to write your own main.
 #[proc_macro]
 pub fn setup_server(input: TokenStream) -> TokenStream {
     let app_name: String;
@@ -221,20 +783,10 @@ pub fn setup_server(input: TokenStream) -> TokenStream {
 
     let web_crate = Ident::new(&format!("{}_web", app_name)[..], Span::call_site());
 
-    let controllers: Vec<proc_macro2::TokenStream> = proto_data
+    let services: Vec<proc_macro2::TokenStream> = proto_data
         .services
         .iter()
         .map(|service| {
-            let controller_var_name = Ident::new(
-                &format!("{}_controller", service.name.to_case(Case::Snake))[..],
-                Span::call_site(),
-            );
-
-            let controller_snake_name = Ident::new(
-                &format!("{}", service.name.to_case(Case::Snake))[..],
-                Span::call_site(),
-            );
-
             let controller_name = Ident::new(
                 &format!(
                     "{}Controller",
@@ -242,21 +794,6 @@ pub fn setup_server(input: TokenStream) -> TokenStream {
                 )[..],
                 Span::call_site(),
             );
-
-            quote! {
-                let #controller_var_name = #web_crate::controllers::#controller_snake_name::#controller_name::new();
-            }
-        })
-        .collect();
-
-    let services: Vec<proc_macro2::TokenStream> = proto_data
-        .services
-        .iter()
-        .map(|service| {
-            let controller_var_name = Ident::new(
-                &format!("{}_controller", service.name.to_case(Case::Snake))[..],
-                Span::call_site(),
-            );
             let service_name = Ident::new(
                 &format!("{}Server", service.name.to_case(Case::UpperCamel))[..],
                 Span::call_site(),
@@ -271,23 +808,90 @@ pub fn setup_server(input: TokenStream) -> TokenStream {
                 Span::call_site(),
             );
 
+            let full_name = service.full_name();
+
             quote! {
-                .add_service(#web_crate::protos::#controller_snake_name::#server_snake_name::#service_name::new(#controller_var_name))
+                .add_controller({
+                    let config = config.clone();
+                    move |controller: #web_crate::controllers::#controller_snake_name::#controller_name| {
+                        let service = #web_crate::protos::#controller_snake_name::#server_snake_name::#service_name::new(controller);
+                        let service = match config.server.max_decoding_message_size {
+                            Some(size) => service.max_decoding_message_size(size),
+                            None => service,
+                        };
+                        let service = match config.server.max_encoding_message_size {
+                            Some(size) => service.max_encoding_message_size(size),
+                            None => service,
+                        };
+                        match config.server.compression_for(#full_name) {
+                            Some(encoding) => service.accept_compressed(encoding).send_compressed(encoding),
+                            None => service,
+                        }
+                    }
+                })
             }
         })
         .collect();
 
-    let mut body = quote! {
-        // Setup tokio_console if setup
-
-        if (#server_config.tokio_console) {
-            console_subscriber::init();
+    let mut auth_policies: Vec<proc_macro2::TokenStream> = Vec::new();
+    let mut anonymous_methods: Vec<String> = Vec::new();
+    for service in proto_data.services.iter() {
+        for rpc in service.rpcs.iter() {
+            if let Some(rule) = &rpc.auth {
+                let method = format!("{}/{}", service.full_name(), rpc.name);
+                if rule.allow_anonymous {
+                    anonymous_methods.push(method.clone());
+                }
+                if !rule.roles.is_empty() {
+                    let roles = &rule.roles;
+                    auth_policies.push(quote! {
+                        cali_core::middleware::authorization::Policy::new(#method, &[#(#roles),*])
+                    });
+                }
+            }
         }
+    }
 
-        // Setup logging
-        cali_core::logging::util::setup();
+    let proto_messages = get_proto_messages(&[path, Path::new("./interface/grpc/models")]);
+    let schema_messages: Vec<proc_macro2::TokenStream> = proto_messages
+        .iter()
+        .map(|message| {
+            let name = message.full_name();
+            let fields = message.fields.iter().map(|field| {
+                let number = field.number;
+                let field_name = &field.name;
+                let kind = &field.kind;
+                let sensitive = field.sensitive;
+                quote! {
+                    cali_core::protos::wire::FieldSchema::new(#number, #field_name, #kind, #sensitive)
+                }
+            });
+            quote! {
+                .message(#name, vec![#(#fields),*])
+            }
+        })
+        .collect();
+    let schema_rpcs: Vec<proc_macro2::TokenStream> = proto_data
+        .services
+        .iter()
+        .flat_map(|service| {
+            // The rpc names its messages without a package, they are the service's own
+            let qualify = move |message: &str| match service.package.as_str() {
+                "" => message.to_string(),
+                package => format!("{}.{}", package, message),
+            };
+            service.rpcs.iter().map(move |rpc| {
+                let method = format!("{}/{}", service.full_name(), rpc.name);
+                let request = qualify(&rpc.request_name);
+                let response = qualify(&rpc.response_name);
+                quote! {
+                    .rpc(#method, #request, #response)
+                }
+            })
+        })
+        .collect();
 
-        log::info!("Getting ready...");
+    let mut body = quote! {
         // Configure CLI App
         let matches = clap::App::new(#app_name)
             .version(#version)
@@ -304,56 +908,51 @@ pub fn setup_server(input: TokenStream) -> TokenStream {
 
 
         // Setup Config File
-        log::info!("Loading config...");
         let config_file = std::fs::File::open(matches.value_of("config")
                             .expect("No value set for config path"))
                             .expect("Could not open config file at web/config/dev.yml");
 
+        let mut unused_fields = Vec::new();
         let config: std::sync::Arc<Config> = std::sync::Arc::new({
             let deserializer = serde_yaml::Deserializer::from_reader(config_file);
             let config: Config = serde_ignored::deserialize(deserializer, |path| {
-                log::warn!("Unused config field: {}", path);
+                unused_fields.push(path.to_string());
             })
             .expect("Could not deserialize config");
             // Edit config here if you want to
             config
         });
-        log::info!("Config loaded!");
-
-        let db_pool = if (#server_config.database) {
-            log::info!("Connecting to DB...");
-            let pool = sqlx::mysql::MySqlPoolOptions::new()
-                .max_connections(config.database.num_connections)
-                .test_before_acquire(true)
-                .connect(&config.database.url)
-                .await?;
-            log::info!("Connected!");
-            Some(pool)
+
+        // Setup logging, tracing and tokio_console if setup
+        let console_layer: Option<cali_core::logging::telemetry::BoxedLayer> = if (#server_config.tokio_console) {
+            Some(Box::new(console_subscriber::spawn()))
         } else {
             None
         };
+        let _telemetry = cali_core::logging::telemetry::init(#app_name, &config.tracing, console_layer);
 
-        let server_ctx : std::sync::Arc<cali_core::ServerContext> = std::sync::Arc::new(cali_core::ServerContext { db_pool });
-
-        let context_layer = cali_core::middleware::server_context::ServerContextLayer {
-            config: config.clone(),
-            extendable_context: #server_config.global_context.clone(),
-            internal_context: server_ctx.clone()
-        };
-
-
-        #(#controllers)*
-
-        let (host, port) = cali_core::helpers::split_host_and_port(&config.bind_address);
-        let addr = format!("{}:{}", host, port);
-
-        let server = if let Some(middleware_fn) = #server_config.middleware_setup {
-            (middleware_fn)(tonic::transport::Server::builder()
-                .layer(context_layer))
-        } else {
-            tonic::transport::Server::builder()
-                .layer(context_layer)
-        }#(#services)*;;
+        tracing::info!("Getting ready...");
+        for path in unused_fields.iter() {
+            tracing::warn!("Unused config field: {}", path);
+        }
+        tracing::info!("Config loaded!");
+
+        let server = cali_core::server::CaliServer::new(config.clone(), #server_config)
+            .bind_address(&config.bind_address)
+            .database(&config.database)
+            .databases(&config.databases)
+            .server(&config.server)
+            .auth(config.auth.as_ref())
+            .allow_anonymous(&[#(#anonymous_methods),*])
+            .policies(vec![
+                #(#auth_policies),*
+            ])
+            .rate_limit(config.rate_limit.as_ref())
+            .timeouts(config.timeouts.as_ref())
+            .access_log(
+                config.access_log.as_ref(),
+                cali_core::protos::wire::Schema::new()#(#schema_messages)*#(#schema_rpcs)*,
+            )#(#services)*;
 
 
     };
@@ -362,24 +961,20 @@ pub fn setup_server(input: TokenStream) -> TokenStream {
         let mut interrupt_signal = tokio::signal::ctrl_c();
         let closer = async move {
             let _ = interrupt_signal.await;
-            log::info!("Goodbye!");
+            tracing::info!("Goodbye!");
         };
 
-        log::info!("GRPC server started, waiting for requests...");
         server
-            .serve_with_shutdown(
-                std::net::SocketAddr::from_str(&addr[..]).unwrap(),
-                async move {
-                    // Add closers for other processes
-                    let _ = closer.await;
-                },
-            )
+            .serve_with_shutdown(async move {
+                // Add closers for other processes
+                let _ = closer.await;
+            })
             .await?;
 
     };
 
     let no_server_segment = quote! {
-        log::info!("No GRPC services have been defined, terminating server.");
+        tracing::info!("No GRPC services have been defined, terminating server.");
     };
 
     if services.len() > 0 {
@@ -393,8 +988,8 @@ pub fn setup_server(input: TokenStream) -> TokenStream {
 
 /// Usually found in tests/common/mod.rs, this defines run/2 which takes a reference to the server
 /// config file, and the test function. This creates a new test db, runs the required migrations
-/// and injects the required global context. This makes use of your previously configured cali
-/// context.
+/// and injects the required global context, along with an empty request context. This makes use
+/// of your previously configured cali context.
 #[proc_macro]
 pub fn test_runner(_input: TokenStream) -> TokenStream {
     // Rather let this return a wrapping type called test context under cali core?
@@ -412,36 +1007,20 @@ pub fn test_runner(_input: TokenStream) -> TokenStream {
             config
         };
 
-        let db_url = url::Url::parse(&config.clone().database.url).expect("Unable to parse DB url");
-
-        // Create the database
-        let pool = sqlx::MySqlPool::connect(&db_url[..url::Position::BeforePath])
+        // Start from an empty database
+        cali_core::store::recreate_database(&config.database.url)
             .await
-            .unwrap();
-
-        let db_name = db_url
-            .path_segments()
-            .expect("No database specified")
-            .next()
-            .expect("No database specified");
-
-        // Delete the existing database
-        let drop_query = format!("DROP DATABASE IF EXISTS {}", db_name);
-        sqlx::query(&drop_query).execute(&pool).await.unwrap();
-
-        // Recreate it
-        let create_query = format!("CREATE DATABASE IF NOT EXISTS {}", db_name);
-        sqlx::query(&create_query).execute(&pool).await.unwrap();
+            .expect("Could not recreate the test database");
 
         // Run all migrations
-        let pool = sqlx::MySqlPool::connect(&db_url.to_string()).await.unwrap();
+        let pool = cali_core::store::DbPool::connect(&config.database.url).await.unwrap();
 
         sqlx::migrate!("../store/migrations")
             .run(&pool)
             .await
             .expect("Expected to be able to run migrations");
 
-        let db_pool = Some(sqlx::mysql::MySqlPoolOptions::new()
+        let db_pool = Some(cali_core::store::DbPoolOptions::new()
             .max_connections(1)
             .test_before_acquire(true)
             .connect(&config.clone().database.url)
@@ -453,7 +1032,7 @@ pub fn test_runner(_input: TokenStream) -> TokenStream {
 
         context.insert(
             std::any::TypeId::of::<cali_core::ServerContext>(),
-            std::sync::Arc::new(cali_core::ServerContext { db_pool }),
+            std::sync::Arc::new(cali_core::ServerContext { db_pool, ..Default::default() }),
         );
 
 
@@ -462,11 +1041,76 @@ pub fn test_runner(_input: TokenStream) -> TokenStream {
             std::sync::Arc::new(config),
         );
 
+        let request_context = cali_core::middleware::request_context::RequestContext::default();
+
         cali_core::SERVER_CONTEXT
-            .scope(std::sync::Arc::new(context), test)
+            .scope(
+                std::sync::Arc::new(context),
+                cali_core::REQUEST_CONTEXT.scope(request_context, test),
+            )
             .await
     }
             };
 
     test_setup_body.into()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upsert_columns_of(input: DeriveInput) -> Option<(Vec<String>, Vec<String>)> {
+        let fields = match input.data {
+            syn::Data::Struct(syn::DataStruct {
+                fields: syn::Fields::Named(named_fields),
+                ..
+            }) => ensnare_fields(&named_fields).unwrap(),
+            _ => unreachable!(),
+        };
+        upsert_columns(&fields, &ensnare_options(&input.attrs).unwrap())
+    }
+
+    #[test]
+    fn upserts_leave_the_primary_key_alone() {
+        let (conflict, upsert) = upsert_columns_of(syn::parse_quote! {
+            #[snare(on_conflict = "email")]
+            struct Contact {
+                #[snare(primary_key)]
+                id: i64,
+                email: String,
+                name: String,
+            }
+        })
+        .unwrap();
+
+        assert_eq!(conflict, vec!["email"]);
+        assert_eq!(upsert, vec!["name"]);
+    }
+
+    #[test]
+    fn upserts_only_marked_columns() {
+        let (conflict, upsert) = upsert_columns_of(syn::parse_quote! {
+            struct Contact {
+                #[snare(primary_key)]
+                id: i64,
+                email: String,
+                #[snare(upsert)]
+                name: String,
+            }
+        })
+        .unwrap();
+
+        assert_eq!(conflict, vec!["id"]);
+        assert_eq!(upsert, vec!["name"]);
+    }
+
+    #[test]
+    fn no_upsert_without_conflict_columns() {
+        assert!(upsert_columns_of(syn::parse_quote! {
+            struct Contact {
+                email: String,
+            }
+        })
+        .is_none());
+    }
+}
@@ -0,0 +1,41 @@
+use cali_core::store::snare::Ensnared;
+use cali_derive::Ensnare;
+
+#[derive(Clone, Ensnare, sqlx::FromRow)]
+#[snare(table = "profiles")]
+struct Profile {
+    #[snare(primary_key)]
+    id: i64,
+    name: String,
+}
+
+fn profile(id: i64) -> Profile {
+    Profile {
+        id,
+        name: "Jane".to_string(),
+    }
+}
+
+#[test]
+fn statements_use_the_table_override() {
+    let mut snare = profile(1).trap();
+    let _ = snare.insert();
+    assert!(snare
+        .query
+        .starts_with("INSERT INTO profiles (id,name) VALUES "));
+
+    let _ = snare.update();
+    assert!(snare.query.starts_with("UPDATE profiles SET name = "));
+
+    let mut select = Profile::select();
+    let _ = select.all();
+    assert_eq!(select.query, "SELECT * FROM profiles");
+
+    let statements = Profile::insert_many(vec![profile(1), profile(2)]).statements();
+    assert_eq!(statements.len(), 1);
+    assert!(statements[0].starts_with("INSERT INTO profiles (id,name) VALUES "));
+
+    let mut changeset = ProfileChangeset::new(1).set_name("Joe".to_string()).trap();
+    let _ = changeset.update();
+    assert!(changeset.query.starts_with("UPDATE profiles SET name = "));
+}
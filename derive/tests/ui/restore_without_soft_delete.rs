@@ -0,0 +1,18 @@
+use cali_core::store::snare::Ensnared;
+use cali_derive::Ensnare;
+
+#[derive(Ensnare)]
+struct Account {
+    #[snare(primary_key)]
+    id: i64,
+    name: String,
+}
+
+fn main() {
+    let mut snare = Account {
+        id: 1,
+        name: "Jane".to_string(),
+    }
+    .trap("accounts");
+    let _ = snare.restore();
+}